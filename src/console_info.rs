@@ -2,12 +2,12 @@
 //!
 //! Display transfer progress to the command line
 
-use crate::progress::{Progress, ProgressInfo};
+use crate::error::{ErrorListFormat, ErrorListProgressInfo, SyncError};
+use crate::progress::{Progress, ProgressInfo, SyncOutcome};
 use crate::sync;
-use anyhow::{Context, Error};
+use anyhow::Error;
 use colored::Colorize;
 use humansize::{file_size_opts as options, FileSize};
-use std::fs::OpenOptions;
 use std::io;
 use std::io::Write;
 use std::path::Path;
@@ -15,26 +15,46 @@ use terminal_size::{terminal_size, Width};
 
 #[derive(Debug)]
 pub struct ConsoleProgressInfo {
-    err_file: Option<std::fs::File>,
+    error_list: Option<ErrorListProgressInfo>,
+    dry_run: bool,
+    itemize: bool,
 }
 
 impl ConsoleProgressInfo {
     pub fn new() -> Self {
-        Self { err_file: None }
+        Self {
+            error_list: None,
+            dry_run: false,
+            itemize: false,
+        }
     }
 
     pub fn with_error_list_path(error_list_path: &Path) -> Result<Self, Error> {
-        let err_file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(error_list_path)
-            .with_context(|| {
-                format!("Could not open errfile at '{}'", error_list_path.display())
-            })?;
+        let error_list = ErrorListProgressInfo::new(error_list_path, ErrorListFormat::default())?;
         Ok(Self {
-            err_file: Some(err_file),
+            error_list: Some(error_list),
+            dry_run: false,
+            itemize: false,
         })
     }
+
+    /// Set how entries are written to the error list
+    pub fn set_error_list_format(&mut self, format: ErrorListFormat) {
+        if let Some(error_list) = &mut self.error_list {
+            error_list.set_format(format);
+        }
+    }
+
+    /// Display one line for each entry that was changed, telling what was
+    /// different, like `rsync --itemize-changes`
+    pub fn set_itemize(&mut self, itemize: bool) {
+        self.itemize = itemize;
+    }
+
+    /// In dry-run mode, display the planned action for each entry
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
 }
 
 impl ProgressInfo for ConsoleProgressInfo {
@@ -53,6 +73,30 @@ impl ProgressInfo for ConsoleProgressInfo {
 
     fn new_file(&mut self, _name: &str) {}
 
+    fn synced(&mut self, name: &str, outcome: &SyncOutcome) {
+        if self.itemize {
+            if let Some(itemized) = outcome.itemize() {
+                println!("{} {}", itemized, name);
+            }
+            return;
+        }
+        if !self.dry_run {
+            return;
+        }
+        let action = match outcome {
+            SyncOutcome::UpToDate => return,
+            SyncOutcome::AttributesUpdated { .. } => "update attributes",
+            SyncOutcome::FileCopied { .. } => "copy",
+            SyncOutcome::FilePatched { .. } => "patch",
+            SyncOutcome::SymlinkCreated => "create symlink",
+            SyncOutcome::SymlinkUpdated => "update symlink",
+            SyncOutcome::DirCreated => "create directory",
+            SyncOutcome::HardLinked => "hard link",
+            SyncOutcome::Deleted => "delete",
+        };
+        println!("{} {}", action.color("yellow"), name);
+    }
+
     fn progress(&mut self, progress: &Progress) {
         let eta_str = human_seconds(progress.eta);
         let percent_width = 3;
@@ -66,13 +110,15 @@ impl ProgressInfo for ConsoleProgressInfo {
         let line_width = get_terminal_width();
         let file_width = line_width - widgets_width - num_separators - 1;
         let current_file = progress.current_file.clone();
-        let current_file = truncate_lossy(&current_file, file_width as usize);
+        let current_file = truncate_lossy(&current_file, file_width);
         let current_file = format!(
             "{filename:<pad$}",
-            pad = file_width as usize,
+            pad = file_width,
             filename = current_file
         );
-        let file_percent = (progress.file_done * 100) / progress.file_size;
+        let file_percent = (progress.file_done * 100)
+            .checked_div(progress.file_size)
+            .unwrap_or(100);
         print!(
             "{:>3}% {}/{} {} {:<}\r",
             file_percent, index, num_files, current_file, eta_str
@@ -80,13 +126,10 @@ impl ProgressInfo for ConsoleProgressInfo {
         let _ = io::stdout().flush();
     }
 
-    fn error(&mut self, entry: &str, desc: &str) {
-        eprintln!("Errror: {}", desc);
-        if let Some(err_file) = &mut self.err_file {
-            // Ignoring errrors when trying to log errors ...
-            let _ = err_file.write(entry.as_bytes());
-            let _ = err_file.write(b"\n");
-            let _ = err_file.flush();
+    fn error(&mut self, error: &SyncError) {
+        eprintln!("Errror: {}", error);
+        if let Some(error_list) = &mut self.error_list {
+            error_list.error(error);
         }
     }
 
@@ -101,6 +144,15 @@ impl ProgressInfo for ConsoleProgressInfo {
             "{} files copied, {} symlinks created, {} symlinks updated",
             stats.copied, stats.symlink_created, stats.symlink_updated
         );
+        if stats.dirs_created != 0 {
+            println!("{} directories created", stats.dirs_created);
+        }
+        if stats.hard_linked != 0 {
+            println!("{} hard links created", stats.hard_linked);
+        }
+        if stats.deleted != 0 {
+            println!("{} entries deleted", stats.deleted);
+        }
         let transfered = stats.total_transfered;
         // We know transfered cannot be negative
         let transfered = transfered.file_size(options::DECIMAL).unwrap();
@@ -109,9 +161,26 @@ impl ProgressInfo for ConsoleProgressInfo {
         let duration = std::time::Duration::from_secs(duration.as_secs());
         let duration = humantime::format_duration(duration);
         println!("{} copied in {}", transfered, duration);
+        if stats.matched_bytes != 0 {
+            let literal = stats.literal_bytes.file_size(options::DECIMAL).unwrap();
+            let matched = stats.matched_bytes.file_size(options::DECIMAL).unwrap();
+            println!(
+                "{} written, {} reused from the destination",
+                literal, matched
+            );
+        }
         if stats.errors != 0 {
             eprintln!("{} errors occurred", stats.errors);
         }
+        if stats.cancelled {
+            eprintln!("Sync was cancelled: some entries were not synced");
+        }
+        if self.dry_run {
+            println!(
+                "{} Dry run: destination was left untouched",
+                "::".color("blue")
+            );
+        }
     }
 }
 
@@ -0,0 +1,180 @@
+//! control
+//!
+//! Pause, resume or cancel a sync running in the background. See `Syncer::spawn()`
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::JoinHandle;
+
+use anyhow::{anyhow, Error};
+
+use crate::sync::Stats;
+
+/// Returned by the workers when they stop because the sync was cancelled
+#[derive(Debug)]
+pub(crate) struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Sync was cancelled")
+    }
+}
+
+impl error::Error for Cancelled {}
+
+impl From<Cancelled> for io::Error {
+    fn from(cancelled: Cancelled) -> Self {
+        io::Error::new(io::ErrorKind::Interrupted, cancelled)
+    }
+}
+
+/// Wether `error` was caused by the cancellation of the sync
+pub(crate) fn is_cancellation(error: &Error) -> bool {
+    error.chain().any(|cause| {
+        cause.is::<Cancelled>()
+            || cause
+                .downcast_ref::<io::Error>()
+                .and_then(|e| e.get_ref())
+                .is_some_and(|inner| inner.is::<Cancelled>())
+    })
+}
+
+#[derive(Debug, Default)]
+struct ControlState {
+    paused: bool,
+    cancelled: bool,
+}
+
+/// Shared by the handle and the workers, which call `checkpoint()`
+/// between entries and between chunks of data
+#[derive(Debug, Default)]
+pub(crate) struct SyncControl {
+    state: Mutex<ControlState>,
+    changed: Condvar,
+}
+
+impl SyncControl {
+    fn state(&self) -> MutexGuard<'_, ControlState> {
+        // The state is always valid, even if a thread panicked while holding the lock
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn update<F: FnOnce(&mut ControlState)>(&self, f: F) {
+        f(&mut self.state());
+        self.changed.notify_all();
+    }
+
+    pub fn cancel(&self) {
+        self.update(|state| state.cancelled = true);
+    }
+
+    pub fn pause(&self) {
+        self.update(|state| state.paused = true);
+    }
+
+    pub fn resume(&self) {
+        self.update(|state| state.paused = false);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state().cancelled
+    }
+
+    /// Block while the sync is paused. Return an error if it was cancelled
+    pub fn checkpoint(&self) -> Result<(), Cancelled> {
+        let mut state = self.state();
+        while state.paused && !state.cancelled {
+            state = self
+                .changed
+                .wait(state)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        if state.cancelled {
+            return Err(Cancelled);
+        }
+        Ok(())
+    }
+}
+
+/// A sync running in a background thread, returned by `Syncer::spawn()`
+pub struct SyncHandle {
+    control: Arc<SyncControl>,
+    thread: JoinHandle<Result<Stats, Error>>,
+}
+
+impl SyncHandle {
+    pub(crate) fn new(control: Arc<SyncControl>, thread: JoinHandle<Result<Stats, Error>>) -> Self {
+        Self { control, thread }
+    }
+
+    /// Stop the sync as soon as possible. The file being copied is not
+    /// left half-written in the destination, and the entries that were not
+    /// synced yet are left alone. Use `join()` to wait for the sync to stop
+    pub fn cancel(&self) {
+        self.control.cancel();
+    }
+
+    /// Suspend the sync after the current chunk of data
+    pub fn pause(&self) {
+        self.control.pause();
+    }
+
+    /// Continue a paused sync
+    pub fn resume(&self) {
+        self.control.resume();
+    }
+
+    /// Wether the sync is over, for instance because it was cancelled
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Wait for the sync to finish. The stats tell what was done, and
+    /// wether the sync was cancelled
+    pub fn join(self) -> Result<Stats, Error> {
+        self.thread
+            .join()
+            .map_err(|e| anyhow!("Could not join sync thread: {:?}", e))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn checkpoint_waits_while_paused() {
+        let control = Arc::new(SyncControl::default());
+        control.pause();
+        let worker_control = control.clone();
+        let worker = thread::spawn(move || worker_control.checkpoint().is_ok());
+        thread::sleep(Duration::from_millis(50));
+        assert!(!worker.is_finished());
+        control.resume();
+        assert!(worker.join().unwrap());
+    }
+
+    #[test]
+    fn cancel_wakes_up_paused_workers() {
+        let control = Arc::new(SyncControl::default());
+        control.pause();
+        let worker_control = control.clone();
+        let worker = thread::spawn(move || worker_control.checkpoint().is_err());
+        control.cancel();
+        assert!(worker.join().unwrap());
+        assert!(control.is_cancelled());
+    }
+
+    #[test]
+    fn detect_cancellation_in_error_chain() {
+        let io_error: io::Error = Cancelled.into();
+        let error = Error::new(io_error).context("Could not copy 'foo'");
+        assert!(is_cancellation(&error));
+        assert!(is_cancellation(&Error::new(Cancelled)));
+        assert!(!is_cancellation(&anyhow!("Could not copy 'foo'")));
+    }
+}
@@ -0,0 +1,424 @@
+//! delta
+//!
+//! rsync-like delta transfer: the existing destination file (the "basis") is
+//! split in blocks, and the source is scanned with a rolling checksum to find
+//! the blocks it has in common with the basis. Those blocks are reused, and
+//! only the remaining (literal) bytes are read from the source.
+
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use anyhow::{Context, Error};
+
+const MIN_BLOCK_SIZE: usize = 1024;
+const MAX_BLOCK_SIZE: usize = 128 * 1024;
+// Literal bytes are written as soon as there are that many of them
+const MAX_LITERAL_SIZE: usize = 100 * 1024;
+
+/// Number of bytes written from the source, and reused from the basis
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct DeltaStats {
+    pub literal: u64,
+    pub matched: u64,
+}
+
+/// Use bigger blocks for bigger files, like rsync does
+pub fn block_size(basis_size: u64) -> usize {
+    let size = (basis_size as f64).sqrt() as usize;
+    size.clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+/// Adler-32 like checksum, that can be updated in constant time when
+/// the window slides by one byte
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl RollingChecksum {
+    fn new(data: &[u8]) -> RollingChecksum {
+        let len = data.len() as u32;
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for (i, &x) in data.iter().enumerate() {
+            a = a.wrapping_add(x as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(x as u32));
+        }
+        RollingChecksum {
+            a: a & 0xffff,
+            b: b & 0xffff,
+            len,
+        }
+    }
+
+    fn roll(&mut self, removed: u8, added: u8) {
+        self.a = self
+            .a
+            .wrapping_sub(removed as u32)
+            .wrapping_add(added as u32)
+            & 0xffff;
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(removed as u32))
+            .wrapping_add(self.a)
+            & 0xffff;
+    }
+
+    fn digest(&self) -> u32 {
+        (self.b << 16) | self.a
+    }
+}
+
+struct Block {
+    offset: u64,
+    len: usize,
+    strong: blake3::Hash,
+}
+
+/// Weak and strong checksums of every block of the basis
+pub struct Signature {
+    block_size: usize,
+    blocks: Vec<Block>,
+    by_weak: HashMap<u32, Vec<usize>>,
+}
+
+impl Signature {
+    pub fn compute<R: Read>(basis: &mut R, block_size: usize) -> Result<Signature, Error> {
+        let mut blocks = vec![];
+        let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+        let mut buffer = vec![0; block_size];
+        let mut offset = 0;
+        loop {
+            let len = read_full(basis, &mut buffer).context("Could not read basis")?;
+            if len == 0 {
+                break;
+            }
+            let data = &buffer[..len];
+            let weak = RollingChecksum::new(data).digest();
+            by_weak.entry(weak).or_default().push(blocks.len());
+            blocks.push(Block {
+                offset,
+                len,
+                strong: blake3::hash(data),
+            });
+            offset += len as u64;
+        }
+        Ok(Signature {
+            block_size,
+            blocks,
+            by_weak,
+        })
+    }
+
+    fn find(&self, weak: u32, data: &[u8]) -> Option<&Block> {
+        let candidates = self.by_weak.get(&weak)?;
+        let strong = blake3::hash(data);
+        candidates
+            .iter()
+            .map(|&index| &self.blocks[index])
+            .find(|block| block.len == data.len() && block.strong == strong)
+    }
+}
+
+/// Write the contents of `source` to `out`, reusing the blocks of `basis`
+/// described in `signature` whenever possible. `on_progress` is called with
+/// the number of source bytes processed so far, and the patch stops as soon
+/// as it returns an error
+pub fn patch<S, B, W, P>(
+    signature: &Signature,
+    source: &mut S,
+    basis: &mut B,
+    out: &mut W,
+    on_progress: P,
+) -> Result<DeltaStats, Error>
+where
+    S: Read,
+    B: Read + Seek,
+    W: Write,
+    P: FnMut(usize) -> io::Result<()>,
+{
+    let mut patcher = Patcher {
+        signature,
+        source,
+        basis,
+        out,
+        on_progress,
+        buffer: vec![],
+        pos: 0,
+        literal_start: 0,
+        eof: false,
+        stats: DeltaStats::default(),
+    };
+    patcher.run()?;
+    Ok(patcher.stats)
+}
+
+struct Patcher<'a, S, B, W, P> {
+    signature: &'a Signature,
+    source: &'a mut S,
+    basis: &'a mut B,
+    out: &'a mut W,
+    on_progress: P,
+    // Source bytes that are not written yet. The current window starts at `pos`,
+    // and the bytes between `literal_start` and `pos` did not match any block
+    buffer: Vec<u8>,
+    pos: usize,
+    literal_start: usize,
+    eof: bool,
+    stats: DeltaStats,
+}
+
+impl<'a, S, B, W, P> Patcher<'a, S, B, W, P>
+where
+    S: Read,
+    B: Read + Seek,
+    W: Write,
+    P: FnMut(usize) -> io::Result<()>,
+{
+    fn run(&mut self) -> Result<(), Error> {
+        let block_size = self.signature.block_size;
+        let mut checksum: Option<RollingChecksum> = None;
+        loop {
+            if !self.fill(block_size)? {
+                // Not enough bytes left for a full block
+                return self.finish();
+            }
+            let window = &self.buffer[self.pos..self.pos + block_size];
+            let weak = checksum
+                .get_or_insert_with(|| RollingChecksum::new(window))
+                .digest();
+            if let Some(block) = self.signature.find(weak, window) {
+                self.copy_block(block)?;
+                checksum = None;
+                continue;
+            }
+            // Slide the window by one byte
+            if !self.fill(block_size + 1)? {
+                self.pos += 1;
+                return self.finish();
+            }
+            let removed = self.buffer[self.pos];
+            let added = self.buffer[self.pos + block_size];
+            if let Some(checksum) = checksum.as_mut() {
+                checksum.roll(removed, added);
+            }
+            self.pos += 1;
+            if self.pos - self.literal_start >= MAX_LITERAL_SIZE {
+                self.write_literal()?;
+            }
+        }
+    }
+
+    /// Only the last block of the basis can be shorter than the others,
+    /// so the end of the source is either this block or literal bytes
+    fn finish(&mut self) -> Result<(), Error> {
+        let end = self.buffer.len();
+        let last_block =
+            self.signature.blocks.last().filter(|block| {
+                block.len < self.signature.block_size && block.len <= end - self.pos
+            });
+        if let Some(last_block) = last_block {
+            let start = end - last_block.len;
+            let tail = &self.buffer[start..];
+            let weak = RollingChecksum::new(tail).digest();
+            if let Some(block) = self.signature.find(weak, tail) {
+                self.pos = start;
+                return self.copy_block(block);
+            }
+        }
+        self.pos = end;
+        self.write_literal()
+    }
+
+    /// Make sure at least `len` bytes are available from the current position.
+    /// Return false if the end of the source was reached before that
+    fn fill(&mut self, len: usize) -> Result<bool, Error> {
+        while self.buffer.len() - self.pos < len && !self.eof {
+            // Everything before literal_start has already been written
+            self.buffer.drain(..self.literal_start);
+            self.pos -= self.literal_start;
+            self.literal_start = 0;
+
+            let old_len = self.buffer.len();
+            self.buffer.resize(old_len + MAX_BLOCK_SIZE, 0);
+            let num_read = read_full(self.source, &mut self.buffer[old_len..])
+                .context("Could not read source")?;
+            self.buffer.truncate(old_len + num_read);
+            self.eof = num_read == 0;
+        }
+        Ok(self.buffer.len() - self.pos >= len)
+    }
+
+    fn write_literal(&mut self) -> Result<(), Error> {
+        let literal = &self.buffer[self.literal_start..self.pos];
+        if literal.is_empty() {
+            return Ok(());
+        }
+        self.out
+            .write_all(literal)
+            .context("Could not write literal bytes")?;
+        self.stats.literal += literal.len() as u64;
+        (self.on_progress)(literal.len())?;
+        self.literal_start = self.pos;
+        Ok(())
+    }
+
+    fn copy_block(&mut self, block: &Block) -> Result<(), Error> {
+        self.write_literal()?;
+        let mut data = vec![0; block.len];
+        self.basis
+            .seek(SeekFrom::Start(block.offset))
+            .and_then(|_| self.basis.read_exact(&mut data))
+            .context("Could not read block from basis")?;
+        self.out
+            .write_all(&data)
+            .context("Could not write matched block")?;
+        self.stats.matched += block.len as u64;
+        (self.on_progress)(block.len)?;
+        self.pos += block.len;
+        self.literal_start = self.pos;
+        Ok(())
+    }
+}
+
+/// Like `read()`, but only returns less than `buffer.len()` bytes at the end of the input
+fn read_full<R: Read + ?Sized>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buffer.len() {
+        match reader.read(&mut buffer[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const BLOCK_SIZE: usize = MIN_BLOCK_SIZE;
+
+    // Deterministic, non-repeating test data
+    fn data(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn run_patch(basis: &[u8], source: &[u8]) -> (Vec<u8>, DeltaStats) {
+        let signature = Signature::compute(&mut Cursor::new(basis), BLOCK_SIZE).unwrap();
+        let mut out = vec![];
+        let mut progress = 0;
+        let stats = patch(
+            &signature,
+            &mut Cursor::new(source),
+            &mut Cursor::new(basis),
+            &mut out,
+            |done| {
+                progress += done;
+                Ok(())
+            },
+        )
+        .unwrap();
+        assert_eq!(progress, source.len());
+        (out, stats)
+    }
+
+    #[test]
+    fn rolling_checksum_matches_full_computation() {
+        let data = data(100, 1);
+        let mut rolling = RollingChecksum::new(&data[0..10]);
+        for i in 0..90 {
+            rolling.roll(data[i], data[i + 10]);
+            let expected = RollingChecksum::new(&data[i + 1..i + 11]);
+            assert_eq!(rolling.digest(), expected.digest());
+        }
+    }
+
+    #[test]
+    fn identical_files() {
+        let basis = data(10 * BLOCK_SIZE + 42, 1);
+        let (out, stats) = run_patch(&basis, &basis);
+        assert_eq!(out, basis);
+        assert_eq!(stats.literal, 0);
+        assert_eq!(stats.matched, basis.len() as u64);
+    }
+
+    #[test]
+    fn completely_different_files() {
+        let basis = data(5 * BLOCK_SIZE, 1);
+        let source = data(5 * BLOCK_SIZE, 2);
+        let (out, stats) = run_patch(&basis, &source);
+        assert_eq!(out, source);
+        assert_eq!(stats.literal, source.len() as u64);
+        assert_eq!(stats.matched, 0);
+    }
+
+    #[test]
+    fn bytes_inserted_in_the_middle() {
+        let basis = data(10 * BLOCK_SIZE, 1);
+        let mut source = basis[..3 * BLOCK_SIZE + 10].to_vec();
+        source.extend_from_slice(b"some inserted bytes");
+        source.extend_from_slice(&basis[3 * BLOCK_SIZE + 10..]);
+        let (out, stats) = run_patch(&basis, &source);
+        assert_eq!(out, source);
+        // Only the block containing the insertion cannot be reused
+        assert_eq!(stats.matched, 9 * BLOCK_SIZE as u64);
+        assert_eq!(stats.literal, (BLOCK_SIZE + 19) as u64);
+    }
+
+    #[test]
+    fn block_modified_in_place() {
+        let basis = data(4 * BLOCK_SIZE, 1);
+        let mut source = basis.clone();
+        source[BLOCK_SIZE + 5] ^= 0xff;
+        let (out, stats) = run_patch(&basis, &source);
+        assert_eq!(out, source);
+        assert_eq!(stats.matched, 3 * BLOCK_SIZE as u64);
+        assert_eq!(stats.literal, BLOCK_SIZE as u64);
+    }
+
+    #[test]
+    fn short_last_block_is_reused() {
+        let basis = data(3 * BLOCK_SIZE + 100, 1);
+        let mut source = data(10, 2);
+        source.extend_from_slice(&basis);
+        let (out, stats) = run_patch(&basis, &source);
+        assert_eq!(out, source);
+        assert_eq!(stats.literal, 10);
+        assert_eq!(stats.matched, basis.len() as u64);
+    }
+
+    #[test]
+    fn empty_basis() {
+        let source = data(BLOCK_SIZE + 1, 1);
+        let (out, stats) = run_patch(&[], &source);
+        assert_eq!(out, source);
+        assert_eq!(stats.literal, source.len() as u64);
+    }
+
+    #[test]
+    fn empty_source() {
+        let basis = data(BLOCK_SIZE, 1);
+        let (out, stats) = run_patch(&basis, &[]);
+        assert!(out.is_empty());
+        assert_eq!(stats, DeltaStats::default());
+    }
+
+    #[test]
+    fn block_size_grows_with_file_size() {
+        assert_eq!(block_size(0), MIN_BLOCK_SIZE);
+        assert_eq!(block_size(100 * 1024 * 1024), 10240);
+        assert_eq!(block_size(1 << 40), MAX_BLOCK_SIZE);
+    }
+}
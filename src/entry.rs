@@ -1,24 +1,40 @@
-use std::fs;
 use std::option::Option;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::filesystem::{Filesystem, Metadata};
 
 #[derive(Debug, Clone)]
 pub struct Entry {
     description: String,
     path: PathBuf,
-    metadata: Option<fs::Metadata>,
+    filesystem: Arc<dyn Filesystem>,
+    metadata: Option<Metadata>,
     exists: bool,
     is_link: Option<bool>,
 }
 
 impl Entry {
+    /// An entry of the local filesystem
+    #[cfg(test)]
     pub fn new(description: &str, entry_path: &Path) -> Entry {
-        let mut metadata = fs::metadata(entry_path).ok();
+        let filesystem: Arc<dyn Filesystem> = Arc::new(crate::filesystem::LocalFilesystem);
+        Entry::with_filesystem(&filesystem, description, entry_path)
+    }
+
+    pub fn with_filesystem(
+        filesystem: &Arc<dyn Filesystem>,
+        description: &str,
+        entry_path: &Path,
+    ) -> Entry {
+        let followed = filesystem.metadata(entry_path);
+        let exists = followed.is_ok();
+        let mut metadata = followed.ok();
         let is_link;
-        let symlink_metadata = fs::symlink_metadata(entry_path);
+        let symlink_metadata = filesystem.symlink_metadata(entry_path);
         if let Ok(data) = symlink_metadata {
-            is_link = Some(data.file_type().is_symlink());
+            is_link = Some(data.is_symlink());
             metadata = Some(data);
         } else {
             is_link = None;
@@ -28,7 +44,8 @@ impl Entry {
             description: String::from(description),
             metadata,
             path: entry_path.to_path_buf(),
-            exists: entry_path.exists(),
+            filesystem: filesystem.clone(),
+            exists,
             is_link,
         }
     }
@@ -40,7 +57,13 @@ impl Entry {
     pub fn path(&self) -> &PathBuf {
         &self.path
     }
-    pub fn metadata(&self) -> Option<&fs::Metadata> {
+
+    /// The filesystem the entry belongs to
+    pub fn filesystem(&self) -> &dyn Filesystem {
+        self.filesystem.as_ref()
+    }
+
+    pub fn metadata(&self) -> Option<&Metadata> {
         self.metadata.as_ref()
     }
     pub fn exists(&self) -> bool {
@@ -50,6 +73,16 @@ impl Entry {
     pub fn is_link(&self) -> Option<bool> {
         self.is_link
     }
+
+    /// Wether the entry is a directory (and not a link to a directory)
+    pub fn is_dir(&self) -> bool {
+        self.metadata.as_ref().map(|m| m.is_dir()).unwrap_or(false)
+    }
+
+    /// Wether the entry is a regular file (and not a link to a file)
+    pub fn is_file(&self) -> bool {
+        self.metadata.as_ref().map(|m| m.is_file()).unwrap_or(false)
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,351 @@
+//! error
+//!
+//! Errors reported for the entries that could not be synced
+
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Context, Error};
+
+use crate::progress::ProgressInfo;
+
+/// What rusync was doing when an error occurred
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorPhase {
+    /// Listing the entries of the source
+    Walk,
+    /// Reading the contents of a file
+    Read,
+    /// Creating or writing an entry in the destination
+    Write,
+    /// Creating or updating a symlink
+    Symlink,
+    /// Creating a hard link
+    HardLink,
+    /// Copying permissions
+    Permissions,
+    /// Copying access and modification times
+    Times,
+    /// Changing the owner or group
+    Ownership,
+    /// Copying extended attributes or ACLs
+    Xattrs,
+    /// Removing an entry from the destination
+    Delete,
+}
+
+impl ErrorPhase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorPhase::Walk => "walk",
+            ErrorPhase::Read => "read",
+            ErrorPhase::Write => "write",
+            ErrorPhase::Symlink => "symlink",
+            ErrorPhase::HardLink => "hard-link",
+            ErrorPhase::Permissions => "permissions",
+            ErrorPhase::Times => "times",
+            ErrorPhase::Ownership => "ownership",
+            ErrorPhase::Xattrs => "xattrs",
+            ErrorPhase::Delete => "delete",
+        }
+    }
+}
+
+impl fmt::Display for ErrorPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// An entry that could not be synced
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncError {
+    /// Name of the entry, relative to the destination
+    pub entry: String,
+    pub phase: ErrorPhase,
+    /// Kind of the underlying I/O error, if any
+    pub kind: Option<io::ErrorKind>,
+    /// OS error number of the underlying I/O error, if any
+    pub errno: Option<i32>,
+    /// Description of the error, along with its causes
+    pub details: String,
+}
+
+impl SyncError {
+    /// Build an error for `entry` from `error`. `phase` is used unless a more
+    /// precise one was attached to `error` with `PhaseContext`
+    pub(crate) fn new(entry: &str, phase: ErrorPhase, error: &Error) -> Self {
+        let phase = error
+            .downcast_ref::<PhaseMessage>()
+            .map_or(phase, |message| message.phase);
+        let io_error = error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<io::Error>());
+        Self {
+            entry: entry.to_string(),
+            phase,
+            kind: io_error.map(|e| e.kind()),
+            errno: io_error.and_then(|e| e.raw_os_error()),
+            details: format!("{:#}", error),
+        }
+    }
+
+    /// Wether retrying later may succeed, for instance because the
+    /// entry was modified or locked during the sync
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self.kind,
+            Some(
+                io::ErrorKind::NotFound
+                    | io::ErrorKind::Interrupted
+                    | io::ErrorKind::WouldBlock
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::ResourceBusy
+                    | io::ErrorKind::StaleNetworkFileHandle
+            )
+        )
+    }
+
+    pub(crate) fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "entry": self.entry,
+            "phase": self.phase.as_str(),
+            "kind": self.kind.map(|kind| format!("{:?}", kind)),
+            "errno": self.errno,
+            "transient": self.is_transient(),
+            "details": self.details,
+        })
+    }
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.details)
+    }
+}
+
+/// How to write the list of errors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ErrorListFormat {
+    /// Only the names of the entries, one per line
+    #[default]
+    Names,
+    /// Entry, phase, I/O error kind, errno and details, separated by tabs
+    Tsv,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for ErrorListFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "names" => Ok(ErrorListFormat::Names),
+            "tsv" => Ok(ErrorListFormat::Tsv),
+            "json" => Ok(ErrorListFormat::Json),
+            _ => Err(anyhow!("Invalid error list format: '{}'", s)),
+        }
+    }
+}
+
+impl ErrorListFormat {
+    /// Format `error` as a line of the error list, without the trailing newline
+    pub fn format(&self, error: &SyncError) -> String {
+        match self {
+            ErrorListFormat::Names => error.entry.clone(),
+            ErrorListFormat::Tsv => [
+                escape_tsv(&error.entry),
+                error.phase.to_string(),
+                error
+                    .kind
+                    .map(|kind| format!("{:?}", kind))
+                    .unwrap_or_default(),
+                error.errno.map(|e| e.to_string()).unwrap_or_default(),
+                escape_tsv(&error.details),
+            ]
+            .join("\t"),
+            ErrorListFormat::Json => error.to_json().to_string(),
+        }
+    }
+}
+
+/// Writes one line for each error to the error list, using the given format
+pub struct ErrorListProgressInfo {
+    writer: Box<dyn Write + Send>,
+    format: ErrorListFormat,
+}
+
+impl ErrorListProgressInfo {
+    /// Write the error list at `path`, replacing any previous contents
+    pub fn new(path: &Path, format: ErrorListFormat) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Could not open errfile at '{}'", path.display()))?;
+        Ok(Self::with_writer(Box::new(file), format))
+    }
+
+    pub fn with_writer(writer: Box<dyn Write + Send>, format: ErrorListFormat) -> Self {
+        Self { writer, format }
+    }
+
+    pub fn set_format(&mut self, format: ErrorListFormat) {
+        self.format = format;
+    }
+}
+
+impl fmt::Debug for ErrorListProgressInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ErrorListProgressInfo")
+            .field("format", &self.format)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProgressInfo for ErrorListProgressInfo {
+    fn error(&mut self, error: &SyncError) {
+        // Ignoring errrors when trying to log errors ...
+        let _ = writeln!(self.writer, "{}", self.format.format(error));
+        let _ = self.writer.flush();
+    }
+}
+
+fn escape_tsv(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// Context message telling in which phase an error occurred
+#[derive(Debug)]
+struct PhaseMessage {
+    phase: ErrorPhase,
+    message: String,
+}
+
+impl fmt::Display for PhaseMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Like `anyhow::Context::with_context`, but also records the phase
+/// in which the error occurred
+pub(crate) trait PhaseContext<T> {
+    fn phase_context<F>(self, phase: ErrorPhase, f: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> String;
+}
+
+impl<T, E> PhaseContext<T> for Result<T, E>
+where
+    Result<T, E>: Context<T, E>,
+{
+    fn phase_context<F>(self, phase: ErrorPhase, f: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> String,
+    {
+        self.with_context(|| PhaseMessage {
+            phase,
+            message: f(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn not_found() -> Result<(), io::Error> {
+        // ENOENT
+        Err(io::Error::from_raw_os_error(2))
+    }
+
+    #[test]
+    fn io_details_are_found_in_the_chain() {
+        let error = not_found()
+            .context("Could not open 'foo'")
+            .context("While syncing")
+            .unwrap_err();
+        let sync_error = SyncError::new("foo", ErrorPhase::Write, &error);
+        assert_eq!(sync_error.phase, ErrorPhase::Write);
+        assert_eq!(sync_error.kind, Some(io::ErrorKind::NotFound));
+        assert_eq!(sync_error.errno, Some(2));
+        assert!(sync_error
+            .details
+            .starts_with("While syncing: Could not open 'foo': "));
+        assert!(sync_error.is_transient());
+    }
+
+    #[test]
+    fn phase_context_overrides_the_default_phase() {
+        let error = not_found()
+            .phase_context(ErrorPhase::Read, || "Could not read 'foo'".to_string())
+            .context("While syncing")
+            .unwrap_err();
+        let sync_error = SyncError::new("foo", ErrorPhase::Write, &error);
+        assert_eq!(sync_error.phase, ErrorPhase::Read);
+        assert!(sync_error
+            .details
+            .starts_with("While syncing: Could not read 'foo': "));
+    }
+
+    #[test]
+    fn errors_without_io_cause() {
+        let error = anyhow!("Refusing to replace 'foo'");
+        let sync_error = SyncError::new("foo", ErrorPhase::Symlink, &error);
+        assert_eq!(sync_error.kind, None);
+        assert_eq!(sync_error.errno, None);
+        assert!(!sync_error.is_transient());
+    }
+
+    #[test]
+    fn format_error_list() {
+        let sync_error = SyncError {
+            entry: "a\tb".to_string(),
+            phase: ErrorPhase::Permissions,
+            kind: Some(io::ErrorKind::PermissionDenied),
+            errno: Some(13),
+            details: "Could not set permissions\nfor a".to_string(),
+        };
+        assert_eq!(ErrorListFormat::Names.format(&sync_error), "a\tb");
+        assert_eq!(
+            ErrorListFormat::Tsv.format(&sync_error),
+            "a\\tb\tpermissions\tPermissionDenied\t13\tCould not set permissions\\nfor a"
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&ErrorListFormat::Json.format(&sync_error)).unwrap();
+        assert_eq!(json["entry"], "a\tb");
+        assert_eq!(json["phase"], "permissions");
+        assert_eq!(json["kind"], "PermissionDenied");
+        assert_eq!(json["errno"], 13);
+        assert_eq!(json["transient"], false);
+    }
+
+    #[test]
+    fn error_list_only_has_the_errors_of_the_last_run() -> Result<(), io::Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let path = tmp_dir.path().join("errors.txt");
+        let error = |entry: &str| SyncError::new(entry, ErrorPhase::Write, &anyhow!("failed"));
+
+        let mut error_list = ErrorListProgressInfo::new(&path, ErrorListFormat::Names).unwrap();
+        error_list.error(&error("a_long_file_name"));
+        error_list.error(&error("other"));
+        drop(error_list);
+        let mut error_list = ErrorListProgressInfo::new(&path, ErrorListFormat::Names).unwrap();
+        error_list.error(&error("short"));
+        drop(error_list);
+
+        assert_eq!(std::fs::read_to_string(&path)?, "short\n");
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+use std::ffi::OsString;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use filetime::FileTime;
+
+use super::{EntryKind, FileHandle, Filesystem, Metadata};
+
+/// The filesystem of the machine rusync runs on, used by default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LocalFilesystem;
+
+impl LocalFilesystem {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl From<&fs::Metadata> for Metadata {
+    fn from(metadata: &fs::Metadata) -> Self {
+        let file_type = metadata.file_type();
+        // Other special files are read like regular files, as before
+        let kind = if file_type.is_symlink() {
+            EntryKind::Symlink
+        } else if file_type.is_dir() {
+            EntryKind::Directory
+        } else {
+            EntryKind::File
+        };
+        let accessed = FileTime::from_last_access_time(metadata);
+        let modified = FileTime::from_last_modification_time(metadata);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            Metadata {
+                kind,
+                len: metadata.len(),
+                accessed,
+                modified,
+                mode: metadata.mode() & 0o7777,
+                uid: metadata.uid(),
+                gid: metadata.gid(),
+                dev: metadata.dev(),
+                ino: metadata.ino(),
+                nlink: metadata.nlink(),
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            let mode = if metadata.permissions().readonly() {
+                0o444
+            } else {
+                0o666
+            };
+            Metadata {
+                kind,
+                len: metadata.len(),
+                accessed,
+                modified,
+                mode,
+                uid: 0,
+                gid: 0,
+                dev: 0,
+                ino: 0,
+                nlink: 1,
+            }
+        }
+    }
+}
+
+impl Filesystem for LocalFilesystem {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::metadata(path).map(|metadata| Metadata::from(&metadata))
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::symlink_metadata(path).map(|metadata| Metadata::from(&metadata))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect()
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+        Ok(Box::new(File::create(path)?))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+
+    #[cfg(unix)]
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        std::os::unix::fs::symlink(target, path)
+    }
+
+    #[cfg(not(unix))]
+    fn symlink(&self, _target: &Path, _path: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Symlinks are only supported on Unix",
+        ))
+    }
+
+    fn hard_link(&self, target: &Path, path: &Path) -> io::Result<()> {
+        fs::hard_link(target, path)
+    }
+
+    #[cfg(unix)]
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+
+    #[cfg(not(unix))]
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let mut permissions = fs::metadata(path)?.permissions();
+        permissions.set_readonly(mode & 0o222 == 0);
+        fs::set_permissions(path, permissions)
+    }
+
+    fn set_times(&self, path: &Path, accessed: FileTime, modified: FileTime) -> io::Result<()> {
+        filetime::set_symlink_file_times(path, accessed, modified)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn is_local(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,736 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::io;
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use filetime::FileTime;
+
+use super::{EntryKind, FileHandle, Filesystem, Metadata};
+
+/// Number of symlinks followed before giving up, like Linux does
+const MAX_SYMLINKS: usize = 40;
+
+#[derive(Debug, Clone)]
+enum Contents {
+    File(Vec<u8>),
+    Directory,
+    Symlink(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+struct Inode {
+    contents: Contents,
+    mode: u32,
+    accessed: FileTime,
+    modified: FileTime,
+    nlink: u64,
+}
+
+impl Inode {
+    fn new(contents: Contents, mode: u32) -> Self {
+        let now = FileTime::now();
+        Self {
+            contents,
+            mode,
+            accessed: now,
+            modified: now,
+            nlink: 1,
+        }
+    }
+
+    fn kind(&self) -> EntryKind {
+        match self.contents {
+            Contents::File(_) => EntryKind::File,
+            Contents::Directory => EntryKind::Directory,
+            Contents::Symlink(_) => EntryKind::Symlink,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    /// Inode number of each entry, by normalized path
+    entries: BTreeMap<PathBuf, u64>,
+    inodes: HashMap<u64, Inode>,
+    next_ino: u64,
+    /// Errors returned by every operation on these paths
+    errors: HashMap<PathBuf, io::ErrorKind>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        let mut state = Self {
+            entries: BTreeMap::new(),
+            inodes: HashMap::new(),
+            next_ino: 1,
+            errors: HashMap::new(),
+        };
+        state.insert(root(), Inode::new(Contents::Directory, 0o755));
+        state
+    }
+}
+
+fn root() -> PathBuf {
+    PathBuf::from("/")
+}
+
+/// Make `path` absolute, without `.` nor `..` components. Relative paths
+/// are relative to the root
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = root();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+        }
+    }
+    normalized
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("'{}' does not exist", path.display()),
+    )
+}
+
+fn already_exists(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("'{}' already exists", path.display()),
+    )
+}
+
+fn invalid(message: &str, path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("'{}' {}", path.display(), message),
+    )
+}
+
+impl State {
+    fn check_errors(&self, path: &Path) -> io::Result<()> {
+        match self.errors.get(path) {
+            Some(kind) => Err(io::Error::new(
+                *kind,
+                format!("Injected error for '{}'", path.display()),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn insert(&mut self, path: PathBuf, inode: Inode) -> u64 {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        self.inodes.insert(ino, inode);
+        self.entries.insert(path, ino);
+        ino
+    }
+
+    fn inode(&self, path: &Path) -> io::Result<(u64, &Inode)> {
+        let ino = *self.entries.get(path).ok_or_else(|| not_found(path))?;
+        Ok((ino, &self.inodes[&ino]))
+    }
+
+    /// Follow the symlinks in `path`, and return the path it points to
+    fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        self.resolve_components(path, true)
+    }
+
+    /// Follow the symlinks in the parent directories of `path`, but not
+    /// in its last component, like `symlink_metadata()` does
+    fn resolve_parent(&self, path: &Path) -> io::Result<PathBuf> {
+        self.resolve_components(path, false)
+    }
+
+    fn resolve_components(&self, path: &Path, follow_last: bool) -> io::Result<PathBuf> {
+        let mut resolved = root();
+        // Components still to resolve, the next one last
+        let mut pending: Vec<Component> = path.components().rev().collect();
+        let mut followed = 0;
+        while let Some(component) = pending.pop() {
+            match component {
+                Component::Normal(name) => resolved.push(name),
+                Component::ParentDir => {
+                    resolved.pop();
+                    continue;
+                }
+                Component::Prefix(_) | Component::RootDir => {
+                    resolved = root();
+                    continue;
+                }
+                Component::CurDir => continue,
+            }
+            if pending.is_empty() && !follow_last {
+                break;
+            }
+            let target = match self
+                .entries
+                .get(&resolved)
+                .map(|ino| &self.inodes[ino].contents)
+            {
+                Some(Contents::Symlink(target)) => target,
+                _ => continue,
+            };
+            followed += 1;
+            if followed > MAX_SYMLINKS {
+                return Err(invalid("has too many levels of symlinks", path));
+            }
+            // Relative targets are relative to the directory of the symlink
+            resolved.pop();
+            pending.extend(target.components().rev());
+        }
+        Ok(resolved)
+    }
+
+    fn check_parent_dir(&self, path: &Path) -> io::Result<()> {
+        let parent = path.parent().ok_or_else(|| already_exists(path))?;
+        let parent = self.resolve(parent)?;
+        let (_, inode) = self.inode(&parent)?;
+        if inode.kind() != EntryKind::Directory {
+            return Err(invalid("is not a directory", &parent));
+        }
+        Ok(())
+    }
+
+    /// `path` and everything below it. Paths are ordered component by
+    /// component, so they all come right after `path`
+    fn subtree<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = &'a PathBuf> + 'a {
+        self.entries
+            .range::<Path, _>((Bound::Included(path), Bound::Unbounded))
+            .map(|(entry, _)| entry)
+            .take_while(move |entry| entry.starts_with(path))
+    }
+
+    fn children<'a>(&'a self, path: &'a Path) -> impl Iterator<Item = &'a PathBuf> + 'a {
+        self.subtree(path)
+            .filter(move |entry| entry.parent() == Some(path))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let (ino, inode) = self.inode(path)?;
+        let len = match &inode.contents {
+            Contents::File(data) => data.len() as u64,
+            Contents::Directory => 0,
+            Contents::Symlink(target) => target.as_os_str().len() as u64,
+        };
+        Ok(Metadata {
+            kind: inode.kind(),
+            len,
+            accessed: inode.accessed,
+            modified: inode.modified,
+            mode: inode.mode,
+            uid: 0,
+            gid: 0,
+            dev: 0,
+            ino,
+            nlink: inode.nlink,
+        })
+    }
+
+    /// Remove the entry at `path`, and its inode if it was the last link to it
+    fn unlink(&mut self, path: &Path) {
+        if let Some(ino) = self.entries.remove(path) {
+            let inode = self
+                .inodes
+                .get_mut(&ino)
+                .expect("entries should have an inode");
+            inode.nlink -= 1;
+            if inode.nlink == 0 {
+                self.inodes.remove(&ino);
+            }
+        }
+    }
+}
+
+/// A filesystem that only exists in memory, mostly useful for tests.
+/// Clones share the same contents.
+///
+/// Paths are always relative to the root of the filesystem, so `foo`
+/// and `/foo` are the same entry. Owners and groups are not stored, and
+/// permissions are not enforced.
+///
+/// ```
+/// use rusync::filesystem::MemoryFilesystem;
+///
+/// let filesystem = MemoryFilesystem::new();
+/// filesystem.write("/src/foo.txt", "foo")?;
+/// assert_eq!(filesystem.read("/src/foo.txt")?, b"foo");
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFilesystem {
+    state: Arc<Mutex<State>>,
+}
+
+impl MemoryFilesystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Write `contents` to the file at `path`, creating it and its
+    /// parent directories if needed
+    pub fn write<P: AsRef<Path>, C: AsRef<[u8]>>(&self, path: P, contents: C) -> io::Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        let mut file = self.create(path)?;
+        file.write_all(contents.as_ref())?;
+        file.flush()
+    }
+
+    /// Return the contents of the file at `path`
+    pub fn read<P: AsRef<Path>>(&self, path: P) -> io::Result<Vec<u8>> {
+        let mut contents = vec![];
+        self.open(path.as_ref())?.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    /// Make every operation on `path` fail with an error of the given kind
+    pub fn inject_error<P: AsRef<Path>>(&self, path: P, kind: io::ErrorKind) {
+        self.state().errors.insert(normalize(path.as_ref()), kind);
+    }
+
+    /// Stop returning errors injected with `inject_error()`
+    pub fn clear_errors(&self) {
+        self.state().errors.clear();
+    }
+}
+
+impl Filesystem for MemoryFilesystem {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let path = normalize(path);
+        let state = self.state();
+        state.check_errors(&path)?;
+        let resolved = state.resolve(&path)?;
+        state.metadata(&resolved)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let path = normalize(path);
+        let state = self.state();
+        state.check_errors(&path)?;
+        let path = state.resolve_parent(&path)?;
+        state.metadata(&path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        let path = normalize(path);
+        let state = self.state();
+        state.check_errors(&path)?;
+        let path = state.resolve(&path)?;
+        let (_, inode) = state.inode(&path)?;
+        if inode.kind() != EntryKind::Directory {
+            return Err(invalid("is not a directory", &path));
+        }
+        let names = state
+            .children(&path)
+            .filter_map(|child| child.file_name())
+            .map(|name| name.to_os_string())
+            .collect();
+        Ok(names)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+        let path = normalize(path);
+        let state = self.state();
+        state.check_errors(&path)?;
+        let path = state.resolve(&path)?;
+        let (ino, inode) = state.inode(&path)?;
+        let data = match &inode.contents {
+            Contents::File(data) => data.clone(),
+            _ => return Err(invalid("is not a file", &path)),
+        };
+        Ok(Box::new(MemoryFile {
+            filesystem: self.clone(),
+            ino,
+            cursor: Cursor::new(data),
+            writable: false,
+            dirty: false,
+        }))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn FileHandle>> {
+        let path = normalize(path);
+        let mut state = self.state();
+        state.check_errors(&path)?;
+        let path = state.resolve(&path)?;
+        let ino = match state.entries.get(&path).copied() {
+            Some(ino) => {
+                let inode = state
+                    .inodes
+                    .get_mut(&ino)
+                    .expect("entries should have an inode");
+                match &mut inode.contents {
+                    Contents::File(data) => data.clear(),
+                    _ => return Err(invalid("is not a file", &path)),
+                }
+                inode.modified = FileTime::now();
+                ino
+            }
+            None => {
+                state.check_parent_dir(&path)?;
+                state.insert(path, Inode::new(Contents::File(vec![]), 0o644))
+            }
+        };
+        Ok(Box::new(MemoryFile {
+            filesystem: self.clone(),
+            ino,
+            cursor: Cursor::new(vec![]),
+            writable: true,
+            dirty: false,
+        }))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let path = normalize(path);
+        let mut state = self.state();
+        state.check_errors(&path)?;
+        let mut ancestors: Vec<_> = path.ancestors().collect();
+        ancestors.reverse();
+        for ancestor in ancestors {
+            let resolved = state.resolve(ancestor)?;
+            match state.entries.get(&resolved) {
+                Some(ino) if state.inodes[ino].kind() == EntryKind::Directory => continue,
+                Some(_) => return Err(already_exists(ancestor)),
+                None => {
+                    state.insert(resolved, Inode::new(Contents::Directory, 0o755));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        let path = normalize(path);
+        let state = self.state();
+        state.check_errors(&path)?;
+        let path = state.resolve_parent(&path)?;
+        match &state.inode(&path)?.1.contents {
+            Contents::Symlink(target) => Ok(target.clone()),
+            _ => Err(invalid("is not a symlink", &path)),
+        }
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        let path = normalize(path);
+        let mut state = self.state();
+        state.check_errors(&path)?;
+        let path = state.resolve_parent(&path)?;
+        if state.entries.contains_key(&path) {
+            return Err(already_exists(&path));
+        }
+        state.check_parent_dir(&path)?;
+        let contents = Contents::Symlink(target.to_path_buf());
+        state.insert(path, Inode::new(contents, 0o777));
+        Ok(())
+    }
+
+    fn hard_link(&self, target: &Path, path: &Path) -> io::Result<()> {
+        let (target, path) = (normalize(target), normalize(path));
+        let mut state = self.state();
+        state.check_errors(&target)?;
+        state.check_errors(&path)?;
+        let (target, path) = (state.resolve_parent(&target)?, state.resolve_parent(&path)?);
+        let (ino, inode) = state.inode(&target)?;
+        if inode.kind() == EntryKind::Directory {
+            return Err(invalid("is a directory", &target));
+        }
+        if state.entries.contains_key(&path) {
+            return Err(already_exists(&path));
+        }
+        state.check_parent_dir(&path)?;
+        state.entries.insert(path, ino);
+        state
+            .inodes
+            .get_mut(&ino)
+            .expect("entries should have an inode")
+            .nlink += 1;
+        Ok(())
+    }
+
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let path = normalize(path);
+        let mut state = self.state();
+        state.check_errors(&path)?;
+        let path = state.resolve(&path)?;
+        let (ino, _) = state.inode(&path)?;
+        state
+            .inodes
+            .get_mut(&ino)
+            .expect("entries should have an inode")
+            .mode = mode & 0o7777;
+        Ok(())
+    }
+
+    fn set_times(&self, path: &Path, accessed: FileTime, modified: FileTime) -> io::Result<()> {
+        let path = normalize(path);
+        let mut state = self.state();
+        state.check_errors(&path)?;
+        let path = state.resolve_parent(&path)?;
+        let (ino, _) = state.inode(&path)?;
+        let inode = state
+            .inodes
+            .get_mut(&ino)
+            .expect("entries should have an inode");
+        inode.accessed = accessed;
+        inode.modified = modified;
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from, to) = (normalize(from), normalize(to));
+        let mut state = self.state();
+        state.check_errors(&from)?;
+        state.check_errors(&to)?;
+        let (from, to) = (state.resolve_parent(&from)?, state.resolve_parent(&to)?);
+        let is_dir = state.inode(&from)?.1.kind() == EntryKind::Directory;
+        if from == to {
+            return Ok(());
+        }
+        if is_dir && to.starts_with(&from) {
+            return Err(invalid("cannot be moved inside itself", &from));
+        }
+        state.check_parent_dir(&to)?;
+        if let Ok((_, existing)) = state.inode(&to) {
+            let existing_is_dir = existing.kind() == EntryKind::Directory;
+            if existing_is_dir != is_dir || state.children(&to).next().is_some() {
+                return Err(already_exists(&to));
+            }
+            state.unlink(&to);
+        }
+        // Move the contents of directories along with them
+        let moved: Vec<_> = state.subtree(&from).cloned().collect();
+        for path in moved {
+            let ino = state
+                .entries
+                .remove(&path)
+                .expect("moved path should exist");
+            let rel_path = path
+                .strip_prefix(&from)
+                .expect("moved path should be in from");
+            let new_path = if rel_path.as_os_str().is_empty() {
+                to.clone()
+            } else {
+                to.join(rel_path)
+            };
+            state.entries.insert(new_path, ino);
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let path = normalize(path);
+        let mut state = self.state();
+        state.check_errors(&path)?;
+        let path = state.resolve_parent(&path)?;
+        if state.inode(&path)?.1.kind() == EntryKind::Directory {
+            return Err(invalid("is a directory", &path));
+        }
+        state.unlink(&path);
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let path = normalize(path);
+        let mut state = self.state();
+        state.check_errors(&path)?;
+        let path = state.resolve_parent(&path)?;
+        if state.inode(&path)?.1.kind() != EntryKind::Directory {
+            return Err(invalid("is not a directory", &path));
+        }
+        if path == root() || state.children(&path).next().is_some() {
+            return Err(invalid("is not empty", &path));
+        }
+        state.unlink(&path);
+        Ok(())
+    }
+}
+
+/// A file of a `MemoryFilesystem`. Written data is stored in the
+/// filesystem when the handle is flushed or dropped
+struct MemoryFile {
+    filesystem: MemoryFilesystem,
+    ino: u64,
+    cursor: Cursor<Vec<u8>>,
+    writable: bool,
+    dirty: bool,
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.cursor.read(buf)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.writable {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "File was not opened for writing",
+            ));
+        }
+        self.dirty = true;
+        self.cursor.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        let mut state = self.filesystem.state();
+        // The file may have been removed since it was opened
+        if let Some(inode) = state.inodes.get_mut(&self.ino) {
+            inode.contents = Contents::File(self.cursor.get_ref().clone());
+            inode.modified = FileTime::now();
+        }
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.cursor.seek(pos)
+    }
+}
+
+impl FileHandle for MemoryFile {}
+
+impl Drop for MemoryFile {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_and_read_files() -> io::Result<()> {
+        let filesystem = MemoryFilesystem::new();
+        filesystem.write("/a/b/foo.txt", "foo")?;
+        assert_eq!(filesystem.read("a/b/foo.txt")?, b"foo");
+        assert!(filesystem.metadata(Path::new("/a/b"))?.is_dir());
+        assert_eq!(filesystem.metadata(Path::new("/a/b/foo.txt"))?.len(), 3);
+        assert_eq!(
+            filesystem.read_dir(Path::new("/a"))?,
+            vec![OsString::from("b")]
+        );
+
+        let error = filesystem.read("/a/bar.txt").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        let error = filesystem.create(Path::new("/c/bar.txt")).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        Ok(())
+    }
+
+    #[test]
+    fn follow_symlinks() -> io::Result<()> {
+        let filesystem = MemoryFilesystem::new();
+        filesystem.write("/dir/foo.txt", "foo")?;
+        filesystem.symlink(Path::new("dir/foo.txt"), Path::new("/link"))?;
+
+        assert!(filesystem.metadata(Path::new("/link"))?.is_file());
+        assert!(filesystem
+            .symlink_metadata(Path::new("/link"))?
+            .is_symlink());
+        assert_eq!(filesystem.read("/link")?, b"foo");
+        assert_eq!(
+            filesystem.read_link(Path::new("/link"))?,
+            PathBuf::from("dir/foo.txt")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn follow_symlinks_in_parent_directories() -> io::Result<()> {
+        let filesystem = MemoryFilesystem::new();
+        filesystem.write("/a/b/foo.txt", "foo")?;
+        filesystem.symlink(Path::new("a/b"), Path::new("/link_to_dir"))?;
+        filesystem.symlink(Path::new("../link_to_dir"), Path::new("/a/link"))?;
+
+        assert_eq!(filesystem.read("/link_to_dir/foo.txt")?, b"foo");
+        assert_eq!(filesystem.read("/a/link/foo.txt")?, b"foo");
+        filesystem.write("/link_to_dir/bar.txt", "bar")?;
+        assert_eq!(filesystem.read("/a/b/bar.txt")?, b"bar");
+        filesystem.remove_file(Path::new("/a/link/bar.txt"))?;
+        assert!(filesystem.metadata(Path::new("/a/b/bar.txt")).is_err());
+        // The last component is not followed
+        assert!(filesystem
+            .symlink_metadata(Path::new("/a/link"))?
+            .is_symlink());
+        Ok(())
+    }
+
+    #[test]
+    fn read_dir_only_lists_children() -> io::Result<()> {
+        let filesystem = MemoryFilesystem::new();
+        filesystem.write("/a/b/foo.txt", "foo")?;
+        filesystem.write("/a/bar.txt", "bar")?;
+        filesystem.write("/a.txt", "a")?;
+
+        let mut names = filesystem.read_dir(Path::new("/a"))?;
+        names.sort();
+        assert_eq!(names, vec![OsString::from("b"), OsString::from("bar.txt")]);
+        Ok(())
+    }
+
+    #[test]
+    fn hard_links_share_contents() -> io::Result<()> {
+        let filesystem = MemoryFilesystem::new();
+        filesystem.write("/foo.txt", "foo")?;
+        filesystem.hard_link(Path::new("/foo.txt"), Path::new("/bar.txt"))?;
+        filesystem.write("/bar.txt", "bar")?;
+
+        assert_eq!(filesystem.read("/foo.txt")?, b"bar");
+        let metadata = filesystem.metadata(Path::new("/foo.txt"))?;
+        assert_eq!(metadata.nlink, 2);
+        assert_eq!(
+            metadata.ino,
+            filesystem.metadata(Path::new("/bar.txt"))?.ino
+        );
+        filesystem.remove_file(Path::new("/foo.txt"))?;
+        assert_eq!(filesystem.metadata(Path::new("/bar.txt"))?.nlink, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn rename_directories() -> io::Result<()> {
+        let filesystem = MemoryFilesystem::new();
+        filesystem.write("/a/b/foo.txt", "foo")?;
+        filesystem.rename(Path::new("/a"), Path::new("/c"))?;
+
+        assert_eq!(filesystem.read("/c/b/foo.txt")?, b"foo");
+        assert!(filesystem.metadata(Path::new("/a")).is_err());
+        let error = filesystem.remove_dir(Path::new("/c")).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn injected_errors() -> io::Result<()> {
+        let filesystem = MemoryFilesystem::new();
+        filesystem.write("/foo.txt", "foo")?;
+        filesystem.inject_error("/foo.txt", io::ErrorKind::PermissionDenied);
+
+        let error = filesystem.read("/foo.txt").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+        filesystem.clear_errors();
+        assert_eq!(filesystem.read("/foo.txt")?, b"foo");
+        Ok(())
+    }
+}
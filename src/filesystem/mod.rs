@@ -0,0 +1,140 @@
+//! filesystem
+//!
+//! Where the sources are read and the destination is written. By default, rusync
+//! uses the local filesystem, but any implementation of the [Filesystem](trait.Filesystem.html)
+//! trait can be given to `Syncer::use_filesystem()`.
+//!
+//! [MemoryFilesystem](struct.MemoryFilesystem.html) keeps everything in memory, and can be told
+//! to fail on purpose, which is useful for tests.
+
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use filetime::FileTime;
+
+pub use crate::fsops::EntryKind;
+
+mod local;
+mod memory;
+
+pub use self::local::LocalFilesystem;
+pub use self::memory::MemoryFilesystem;
+
+/// Metadata of an entry, as returned by `Filesystem::metadata()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: EntryKind,
+    /// Size in bytes
+    pub len: u64,
+    pub accessed: FileTime,
+    pub modified: FileTime,
+    /// Permission bits, as used by `chmod`
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// Device and inode numbers, used to find hard links. Zero when
+    /// the filesystem does not have inodes
+    pub dev: u64,
+    pub ino: u64,
+    /// Number of hard links to the entry
+    pub nlink: u64,
+}
+
+impl Metadata {
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.kind == EntryKind::File
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.kind == EntryKind::Directory
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.kind == EntryKind::Symlink
+    }
+}
+
+/// A file opened with `Filesystem::open()` or `Filesystem::create()`
+pub trait FileHandle: Read + Write + Seek + Send {
+    /// The underlying local file, if any. Used to clone files or copy them
+    /// without going through a buffer
+    fn as_file(&mut self) -> Option<&mut File> {
+        None
+    }
+
+    /// Make sure everything written so far is stored
+    fn sync_all(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl FileHandle for File {
+    fn as_file(&mut self) -> Option<&mut File> {
+        Some(self)
+    }
+
+    fn sync_all(&mut self) -> io::Result<()> {
+        File::sync_all(self)
+    }
+}
+
+/// The operations rusync needs to read the sources and write the destination.
+/// They behave like the functions of `std::fs` with the same name
+pub trait Filesystem: fmt::Debug + Send + Sync {
+    /// Metadata of the entry at `path`, following symlinks
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Metadata of the entry at `path`, without following symlinks
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Names of the entries of the directory at `path`, in no particular order
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>>;
+
+    /// Open the file at `path` for reading
+    fn open(&self, path: &Path) -> io::Result<Box<dyn FileHandle>>;
+
+    /// Open the file at `path` for writing, creating it if needed and
+    /// truncating it otherwise
+    fn create(&self, path: &Path) -> io::Result<Box<dyn FileHandle>>;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Create a symlink at `path` pointing to `target`
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()>;
+
+    /// Create a hard link at `path` to the file at `target`
+    fn hard_link(&self, target: &Path, path: &Path) -> io::Result<()>;
+
+    /// Set the permission bits of the entry at `path`
+    fn set_permissions(&self, path: &Path, mode: u32) -> io::Result<()>;
+
+    /// Set access and modification times, without following symlinks
+    fn set_times(&self, path: &Path, accessed: FileTime, modified: FileTime) -> io::Result<()>;
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Remove the directory at `path`, which must be empty
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Wether paths can be given to the operating system directly. Extended
+    /// attributes, ACLs and ownership can only be synced on such filesystems
+    fn is_local(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,321 @@
+//! filter
+//!
+//! Include and exclude rules, using rsync-like glob patterns
+//!
+//! * Rules are checked in order, and the first matching rule wins.
+//! * A pattern starting with `/` is anchored to the root of the source,
+//!   otherwise it matches at any depth.
+//! * `*` matches anything but `/`, and `**` matches anything, including `/`.
+//! * A pattern ending with `/` only matches directories.
+//! * Entries not matched by any rule are included.
+//!
+//! Excluded directories are not walked at all.
+//!
+//! When `SyncOptions::ignore_files` is set, the [IGNORE_FILE_NAMES] files found in each
+//! source directory are also read, using the `.gitignore` syntax. Their rules apply to
+//! the directory they are in and all its subdirectories, and are only checked for entries
+//! not matched by any include or exclude rule.
+
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Error};
+use globset::{GlobBuilder, GlobMatcher};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+
+use crate::filesystem::Filesystem;
+
+/// Names of the ignore files, from lowest to highest precedence
+pub const IGNORE_FILE_NAMES: [&str; 3] = [".gitignore", ".ignore", ".rusyncignore"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterRule {
+    /// Include entries matching the pattern
+    Include(String),
+    /// Exclude entries matching the pattern
+    Exclude(String),
+}
+
+#[derive(Debug, Clone)]
+struct CompiledRule {
+    include: bool,
+    dir_only: bool,
+    matcher: GlobMatcher,
+}
+
+/// Rules read from the ignore files of a directory and of all its parents
+#[derive(Debug, Clone, Default)]
+pub(crate) struct IgnoreStack {
+    // From the outermost directory to the innermost one
+    ignores: Vec<Arc<Gitignore>>,
+}
+
+impl IgnoreStack {
+    fn is_ignored(&self, rel_path: &Path, is_dir: bool) -> bool {
+        // Rules from the innermost directory take precedence
+        for ignore in self.ignores.iter().rev() {
+            match ignore.matched(rel_path, is_dir) {
+                Match::None => continue,
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+            }
+        }
+        false
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Filters {
+    rules: Vec<CompiledRule>,
+    ignore_files: bool,
+}
+
+impl Filters {
+    pub fn new(rules: &[FilterRule], ignore_files: bool) -> Result<Filters, Error> {
+        let rules = rules
+            .iter()
+            .map(compile_rule)
+            .collect::<Result<Vec<_>, Error>>()?;
+        Ok(Filters {
+            rules,
+            ignore_files,
+        })
+    }
+
+    /// Return the rules applying to the entries of the source directory `src_dir`,
+    /// located at `rel_dir` relative to the root of the source, given the rules
+    /// applying to its parent
+    pub fn read_ignore_files(
+        &self,
+        filesystem: &dyn Filesystem,
+        parent: &IgnoreStack,
+        src_dir: &Path,
+        rel_dir: &Path,
+    ) -> Result<IgnoreStack, Error> {
+        let mut stack = parent.clone();
+        if !self.ignore_files {
+            return Ok(stack);
+        }
+        let mut builder = GitignoreBuilder::new(rel_dir);
+        for name in IGNORE_FILE_NAMES {
+            let path = src_dir.join(name);
+            if !filesystem.metadata(&path).is_ok_and(|m| m.is_file()) {
+                continue;
+            }
+            let mut contents = String::new();
+            filesystem
+                .open(&path)
+                .and_then(|mut file| file.read_to_string(&mut contents))
+                .with_context(|| format!("Could not read '{}'", path.display()))?;
+            for line in contents.lines() {
+                if let Err(e) = builder.add_line(Some(path.clone()), line) {
+                    return Err(anyhow!("{}", e))
+                        .with_context(|| format!("Could not read '{}'", path.display()));
+                }
+            }
+        }
+        let ignore = builder
+            .build()
+            .with_context(|| format!("Invalid ignore files in '{}'", src_dir.display()))?;
+        if !ignore.is_empty() {
+            stack.ignores.push(Arc::new(ignore));
+        }
+        Ok(stack)
+    }
+
+    /// Wether the entry at `rel_path` (relative to the root of the source)
+    /// should be skipped. `ignores` are the rules of the directory containing the entry
+    pub fn is_excluded(&self, rel_path: &Path, is_dir: bool, ignores: &IgnoreStack) -> bool {
+        let matching_rule = self
+            .rules
+            .iter()
+            .find(|rule| (is_dir || !rule.dir_only) && rule.matcher.is_match(rel_path));
+        match matching_rule {
+            Some(rule) => !rule.include,
+            None => ignores.is_ignored(rel_path, is_dir),
+        }
+    }
+}
+
+fn compile_rule(rule: &FilterRule) -> Result<CompiledRule, Error> {
+    let (include, pattern) = match rule {
+        FilterRule::Include(pattern) => (true, pattern),
+        FilterRule::Exclude(pattern) => (false, pattern),
+    };
+    let (dir_only, trimmed) = match pattern.strip_suffix('/') {
+        Some(trimmed) => (true, trimmed),
+        None => (false, pattern.as_str()),
+    };
+    let glob = match trimmed.strip_prefix('/') {
+        Some(anchored) => anchored.to_string(),
+        None => format!("**/{}", trimmed),
+    };
+    let matcher = GlobBuilder::new(&glob)
+        .literal_separator(true)
+        .build()
+        .with_context(|| format!("Invalid filter pattern: '{}'", pattern))?
+        .compile_matcher();
+    Ok(CompiledRule {
+        include,
+        dir_only,
+        matcher,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::LocalFilesystem;
+
+    struct TestFilters {
+        filters: Filters,
+    }
+
+    impl TestFilters {
+        fn is_excluded(&self, rel_path: &Path, is_dir: bool) -> bool {
+            self.filters
+                .is_excluded(rel_path, is_dir, &IgnoreStack::default())
+        }
+    }
+
+    fn filters(rules: &[FilterRule]) -> TestFilters {
+        TestFilters {
+            filters: Filters::new(rules, false).unwrap(),
+        }
+    }
+
+    fn exclude(pattern: &str) -> FilterRule {
+        FilterRule::Exclude(pattern.to_string())
+    }
+
+    fn include(pattern: &str) -> FilterRule {
+        FilterRule::Include(pattern.to_string())
+    }
+
+    #[test]
+    fn no_rules_includes_everything() {
+        let filters = filters(&[]);
+        assert!(!filters.is_excluded(Path::new("foo/bar.txt"), false));
+    }
+
+    #[test]
+    fn unanchored_pattern_matches_at_any_depth() {
+        let filters = filters(&[exclude("*.tmp")]);
+        assert!(filters.is_excluded(Path::new("foo.tmp"), false));
+        assert!(filters.is_excluded(Path::new("a/b/foo.tmp"), false));
+        assert!(!filters.is_excluded(Path::new("a/foo.txt"), false));
+    }
+
+    #[test]
+    fn anchored_pattern_only_matches_at_the_root() {
+        let filters = filters(&[exclude("/target")]);
+        assert!(filters.is_excluded(Path::new("target"), true));
+        assert!(!filters.is_excluded(Path::new("sub/target"), true));
+    }
+
+    #[test]
+    fn single_star_does_not_cross_directories() {
+        let filters = filters(&[exclude("/src/*.rs")]);
+        assert!(filters.is_excluded(Path::new("src/lib.rs"), false));
+        assert!(!filters.is_excluded(Path::new("src/workers/mod.rs"), false));
+    }
+
+    #[test]
+    fn double_star_crosses_directories() {
+        let filters = filters(&[exclude("/src/**/*.rs")]);
+        assert!(filters.is_excluded(Path::new("src/workers/mod.rs"), false));
+    }
+
+    #[test]
+    fn trailing_slash_only_matches_directories() {
+        let filters = filters(&[exclude(".git/")]);
+        assert!(filters.is_excluded(Path::new("sub/.git"), true));
+        assert!(!filters.is_excluded(Path::new("sub/.git"), false));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let filters = filters(&[include("keep.tmp"), exclude("*.tmp")]);
+        assert!(!filters.is_excluded(Path::new("keep.tmp"), false));
+        assert!(filters.is_excluded(Path::new("other.tmp"), false));
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        let result = Filters::new(&[exclude("foo[")], false);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn ignore_files_apply_hierarchically() -> Result<(), std::io::Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let src = tmp_dir.path();
+        std::fs::create_dir_all(src.join("sub"))?;
+        std::fs::write(src.join(".gitignore"), "*.log\n/build/\n")?;
+        std::fs::write(src.join("sub/.rusyncignore"), "!keep.log\n")?;
+
+        let filters = Filters::new(&[], true).unwrap();
+        let top = filters
+            .read_ignore_files(
+                &LocalFilesystem,
+                &IgnoreStack::default(),
+                src,
+                Path::new(""),
+            )
+            .unwrap();
+        let sub = filters
+            .read_ignore_files(&LocalFilesystem, &top, &src.join("sub"), Path::new("sub"))
+            .unwrap();
+
+        assert!(filters.is_excluded(Path::new("debug.log"), false, &top));
+        assert!(filters.is_excluded(Path::new("build"), true, &top));
+        assert!(!filters.is_excluded(Path::new("main.rs"), false, &top));
+        assert!(filters.is_excluded(Path::new("sub/debug.log"), false, &sub));
+        assert!(!filters.is_excluded(Path::new("sub/keep.log"), false, &sub));
+        assert!(!filters.is_excluded(Path::new("sub/build"), true, &sub));
+        Ok(())
+    }
+
+    #[test]
+    fn filter_rules_take_precedence_over_ignore_files() -> Result<(), std::io::Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let src = tmp_dir.path();
+        std::fs::write(src.join(".ignore"), "*.log\n")?;
+
+        let filters = Filters::new(&[include("important.log")], true).unwrap();
+        let ignores = filters
+            .read_ignore_files(
+                &LocalFilesystem,
+                &IgnoreStack::default(),
+                src,
+                Path::new(""),
+            )
+            .unwrap();
+
+        assert!(!filters.is_excluded(Path::new("important.log"), false, &ignores));
+        assert!(filters.is_excluded(Path::new("debug.log"), false, &ignores));
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_files_are_not_read_unless_asked() -> Result<(), std::io::Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let src = tmp_dir.path();
+        std::fs::write(src.join(".gitignore"), "*.log\n")?;
+
+        let filters = Filters::new(&[], false).unwrap();
+        let ignores = filters
+            .read_ignore_files(
+                &LocalFilesystem,
+                &IgnoreStack::default(),
+                src,
+                Path::new(""),
+            )
+            .unwrap();
+
+        assert!(!filters.is_excluded(Path::new("debug.log"), false, &ignores));
+        Ok(())
+    }
+}
@@ -1,27 +1,183 @@
-use std::fs;
-use std::fs::File;
+use std::ffi::OsStr;
+use std::io;
+use std::io::BufWriter;
 use std::io::Read;
 use std::io::Write;
-#[cfg(unix)]
-use std::os::unix;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::mpsc;
 
 use anyhow::{bail, Context, Error};
-use filetime::FileTime;
 
+use crate::control::{self, SyncControl};
+use crate::delta;
 use crate::entry::Entry;
+use crate::error::{ErrorPhase, PhaseContext};
+use crate::filesystem::{FileHandle, Filesystem};
 use crate::progress::ProgressMessage;
+#[cfg(target_os = "linux")]
+use crate::reflink;
+use crate::sparse;
+use crate::sync::{ReflinkMode, SyncOptions};
 
 const BUFFER_SIZE: usize = 100 * 1024;
+const TEMP_SUFFIX: &str = ".rusync-tmp";
+
+/// The kind of a synced entry
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+}
+
+impl EntryKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EntryKind::File => "file",
+            EntryKind::Directory => "directory",
+            EntryKind::Symlink => "symlink",
+        }
+    }
+}
+
+/// What differed between the source and the destination before the sync.
+/// Times and permissions are only compared when they are preserved
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct Changes {
+    /// The destination did not exist
+    pub created: bool,
+    /// The files had different sizes
+    pub size: bool,
+    /// The files had the same size but different contents. This is only
+    /// checked with `SyncOptions::checksum`
+    pub contents: bool,
+    /// The modification times were different
+    pub mtime: bool,
+    /// The permissions were different
+    pub permissions: bool,
+    /// The symlinks pointed to different targets
+    pub target: bool,
+}
 
-#[derive(PartialEq, Eq, Debug)]
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        *self == Changes::default()
+    }
+
+    /// Names of the changes, such as `size` or `mtime`
+    pub fn names(&self) -> Vec<&'static str> {
+        [
+            (self.created, "created"),
+            (self.size, "size"),
+            (self.contents, "contents"),
+            (self.mtime, "mtime"),
+            (self.permissions, "permissions"),
+            (self.target, "target"),
+        ]
+        .iter()
+        .filter(|(changed, _)| *changed)
+        .map(|(_, name)| *name)
+        .collect()
+    }
+}
+
+/// What happened to an entry during the sync - or what would have happened,
+/// when running in dry-run mode
+#[derive(PartialEq, Eq, Debug, Clone)]
 pub enum SyncOutcome {
+    /// The destination was already up to date
     UpToDate,
-    FileCopied { size: u64 },
+    /// The contents of the destination were up to date, but some of its
+    /// attributes, such as times or permissions, were updated
+    AttributesUpdated { kind: EntryKind, changes: Changes },
+    /// The file was copied, `size` bytes were written
+    FileCopied { size: u64, changes: Changes },
+    /// The file was rebuilt from the blocks of the existing destination:
+    /// `literal` bytes came from the source and `matched` bytes were reused
+    FilePatched {
+        size: u64,
+        literal: u64,
+        matched: u64,
+        changes: Changes,
+    },
+    /// The destination link was pointing to an other location
     SymlinkUpdated,
+    /// The destination link did not exist
     SymlinkCreated,
+    /// The destination directory did not exist
+    DirCreated,
+    /// The destination was made a hard link to an other destination file,
+    /// like in the source
+    HardLinked,
+    /// The entry did not exist in the source and was removed from the destination
+    Deleted,
+}
+
+impl SyncOutcome {
+    fn up_to_date(kind: EntryKind, changes: Changes) -> SyncOutcome {
+        if changes.is_empty() {
+            SyncOutcome::UpToDate
+        } else {
+            SyncOutcome::AttributesUpdated { kind, changes }
+        }
+    }
+
+    /// A short name for the outcome, such as `copied` or `up_to_date`
+    pub fn name(&self) -> &'static str {
+        match self {
+            SyncOutcome::UpToDate => "up_to_date",
+            SyncOutcome::AttributesUpdated { .. } => "attributes_updated",
+            SyncOutcome::FileCopied { .. } => "copied",
+            SyncOutcome::FilePatched { .. } => "patched",
+            SyncOutcome::SymlinkUpdated => "symlink_updated",
+            SyncOutcome::SymlinkCreated => "symlink_created",
+            SyncOutcome::DirCreated => "dir_created",
+            SyncOutcome::HardLinked => "hard_linked",
+            SyncOutcome::Deleted => "deleted",
+        }
+    }
+
+    /// Describe the outcome like `rsync --itemize-changes` does: the type of
+    /// update, the kind of entry, then one letter for each change
+    /// (c: contents or symlink target, s: size, t: time, p: permissions).
+    /// Return None when the entry was up to date
+    pub fn itemize(&self) -> Option<String> {
+        let flags = |changes: &Changes| {
+            if changes.created {
+                return "++++".to_string();
+            }
+            [
+                (changes.contents || changes.target, 'c'),
+                (changes.size, 's'),
+                (changes.mtime, 't'),
+                (changes.permissions, 'p'),
+            ]
+            .iter()
+            .map(|(changed, letter)| if *changed { *letter } else { '.' })
+            .collect()
+        };
+        let itemized = match self {
+            SyncOutcome::UpToDate => return None,
+            SyncOutcome::AttributesUpdated { kind, changes } => {
+                let kind = match kind {
+                    EntryKind::File => 'f',
+                    EntryKind::Directory => 'd',
+                    EntryKind::Symlink => 'L',
+                };
+                format!(".{}{}", kind, flags(changes))
+            }
+            SyncOutcome::FileCopied { changes, .. } | SyncOutcome::FilePatched { changes, .. } => {
+                format!(">f{}", flags(changes))
+            }
+            SyncOutcome::SymlinkUpdated => "cLc...".to_string(),
+            SyncOutcome::SymlinkCreated => "cL++++".to_string(),
+            SyncOutcome::DirCreated => "cd++++".to_string(),
+            SyncOutcome::HardLinked => "hf++++".to_string(),
+            SyncOutcome::Deleted => "*deleting".to_string(),
+        };
+        Some(itemized)
+    }
 }
 
 pub fn get_rel_path(a: &Path, b: &Path) -> PathBuf {
@@ -29,6 +185,45 @@ pub fn get_rel_path(a: &Path, b: &Path) -> PathBuf {
         .expect("called get_rel_path on two absolute paths '{}' and '{}', a, b")
 }
 
+/// Path of the hidden temporary file used to write `dest_path`
+/// before renaming it
+pub fn temp_path(dest_path: &Path) -> PathBuf {
+    let file_name = dest_path
+        .file_name()
+        .expect("dest path should have a file name")
+        .to_string_lossy();
+    dest_path.with_file_name(format!(".{}{}", file_name, TEMP_SUFFIX))
+}
+
+pub fn is_temp_file(file_name: &OsStr) -> bool {
+    let file_name = file_name.to_string_lossy();
+    file_name.starts_with('.') && file_name.ends_with(TEMP_SUFFIX)
+}
+
+/// Remove temporary files left over in `dir` by an interrupted sync
+pub fn remove_temp_files(filesystem: &dyn Filesystem, dir: &Path) -> Result<(), Error> {
+    let entries = match filesystem.read_dir(dir) {
+        Ok(entries) => entries,
+        // Nothing to clean up in a directory that does not exist yet
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => {
+            return Err(Error::new(e).context(format!(
+                "Could not read '{}' while looking for temporary files",
+                dir.display()
+            )))
+        }
+    };
+    for name in entries {
+        if is_temp_file(&name) {
+            let path = dir.join(name);
+            filesystem
+                .remove_file(&path)
+                .with_context(|| format!("Could not remove temporary file '{}'", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
 fn is_more_recent_than(src: &Entry, dest: &Entry) -> bool {
     if !dest.exists() {
         return true;
@@ -40,10 +235,30 @@ fn is_more_recent_than(src: &Entry, dest: &Entry) -> bool {
     let src_meta = &src_meta.expect("src_meta was None");
     let dest_meta = &dest_meta.expect("dest_meta was None");
 
-    let src_mtime = FileTime::from_last_modification_time(src_meta);
-    let dest_mtime = FileTime::from_last_modification_time(dest_meta);
+    src_meta.modified > dest_meta.modified
+}
 
-    src_mtime > dest_mtime
+/// Compare the attributes of `src` and `dest` that are synced
+fn attribute_changes(src: &Entry, dest: &Entry, opts: &SyncOptions) -> Changes {
+    let (src_meta, dest_meta) = match (src.metadata(), dest.metadata()) {
+        (Some(src_meta), Some(dest_meta)) => (src_meta, dest_meta),
+        _ => {
+            return Changes {
+                created: true,
+                ..Default::default()
+            }
+        }
+    };
+    Changes {
+        size: src_meta.is_file() && src_meta.len() != dest_meta.len(),
+        mtime: opts.preserve_times && src_meta.modified != dest_meta.modified,
+        // Permissions of symlinks are not synced
+        permissions: cfg!(unix)
+            && opts.preserve_permissions
+            && !src_meta.is_symlink()
+            && src_meta.mode != dest_meta.mode,
+        ..Default::default()
+    }
 }
 
 #[cfg(unix)]
@@ -60,40 +275,56 @@ pub fn copy_permissions(src: &Entry, dest: &Entry) -> Result<(), Error> {
     // The only way for src_meta to be None is if src is a broken symlink
     // and we checked that right above:
     let src_meta = &src_meta.unwrap_or_else(|| panic!("src_meta was None for {:#?}", src));
-    let permissions = src_meta.permissions();
-    let dest_file = File::open(dest.path()).with_context(|| {
-        format!(
-            "Could not open '{}' while copying permissions",
-            dest.description()
-        )
-    })?;
-    dest_file
-        .set_permissions(permissions)
+    dest.filesystem()
+        .set_permissions(dest.path(), src_meta.mode)
         .with_context(|| format!("Could not set permissions for {}", dest.description()))?;
     Ok(())
 }
 
-fn copy_link(src: &Entry, dest: &Entry) -> Result<SyncOutcome, Error> {
-    let src_target = std::fs::read_link(src.path())
+/// Set access and modification times of `dest` to those of `src`.
+/// Symlinks are not followed.
+pub fn copy_times(src: &Entry, dest: &Entry) -> Result<(), Error> {
+    let src_meta = src
+        .metadata()
+        .unwrap_or_else(|| panic!("src_meta was None for {:#?}", src));
+    dest.filesystem()
+        .set_times(dest.path(), src_meta.accessed, src_meta.modified)
+        .with_context(|| format!("Could not set times for {}", dest.description()))?;
+    Ok(())
+}
+
+fn copy_link(
+    src: &Entry,
+    dest: &Entry,
+    changes: Changes,
+    opts: &SyncOptions,
+) -> Result<SyncOutcome, Error> {
+    let src_target = src
+        .filesystem()
+        .read_link(src.path())
         .with_context(|| format!("While copying source link '{}'", src.description()))?;
 
     let is_link = dest.is_link();
-    let outcome;
-    match is_link {
+    let outcome = match is_link {
         Some(true) => {
-            let dest_target = std::fs::read_link(dest.path())
+            let dest_target = dest
+                .filesystem()
+                .read_link(dest.path())
                 .with_context(|| format!("While creating target link: {}", dest.description()))?;
-            if dest_target != src_target {
-                fs::remove_file(dest.path()).with_context(|| {
-                    format!(
-                        "Could not remove {} while updating link",
-                        dest.description()
-                    )
-                })?;
-                outcome = SyncOutcome::SymlinkUpdated;
-            } else {
-                return Ok(SyncOutcome::UpToDate);
+            if dest_target == src_target {
+                return Ok(SyncOutcome::up_to_date(EntryKind::Symlink, changes));
+            }
+            if !opts.dry_run {
+                dest.filesystem()
+                    .remove_file(dest.path())
+                    .with_context(|| {
+                        format!(
+                            "Could not remove {} while updating link",
+                            dest.description()
+                        )
+                    })?;
             }
+            SyncOutcome::SymlinkUpdated
         }
         Some(false) => {
             // Never safe to delete
@@ -104,59 +335,363 @@ fn copy_link(src: &Entry, dest: &Entry) -> Result<SyncOutcome, Error> {
         }
         None => {
             // OK, dest does not exist
-            outcome = SyncOutcome::SymlinkCreated;
+            SyncOutcome::SymlinkCreated
         }
+    };
+    if opts.dry_run {
+        return Ok(outcome);
     }
-    #[cfg(unix)]
-    {
-        unix::fs::symlink(&src_target, dest.path()).with_context(|| {
+    // Symlinks are not created on Windows
+    if cfg!(windows) && dest.filesystem().is_local() {
+        return Ok(outcome);
+    }
+    dest.filesystem()
+        .symlink(&src_target, dest.path())
+        .with_context(|| {
             format!(
                 "Could not create link from {} to {}",
                 dest.description(),
                 src.description()
             )
         })?;
-        Ok(outcome)
+    Ok(outcome)
+}
+
+/// Make `dest` a hard link to `target`, the destination of an other
+/// source file having the same inode
+pub fn sync_hard_link(
+    dest: &Entry,
+    target: &Path,
+    opts: &SyncOptions,
+) -> Result<SyncOutcome, Error> {
+    let filesystem = dest.filesystem();
+    if let Some(dest_meta) = dest.metadata() {
+        if dest_meta.is_dir() {
+            bail!(
+                "Refusing to replace existing directory {} by hard link",
+                dest.description()
+            );
+        }
+        // Inode numbers are zero on filesystems without inodes
+        if let Ok(target_meta) = filesystem.metadata(target) {
+            if target_meta.ino != 0
+                && (target_meta.dev, target_meta.ino) == (dest_meta.dev, dest_meta.ino)
+            {
+                return Ok(SyncOutcome::UpToDate);
+            }
+        }
+    }
+    if opts.dry_run {
+        return Ok(SyncOutcome::HardLinked);
+    }
+    // Link to a temporary path first, so that an existing destination
+    // is replaced atomically
+    let temp_path = temp_path(dest.path());
+    let _ = filesystem.remove_file(&temp_path);
+    filesystem.hard_link(target, &temp_path).with_context(|| {
+        format!(
+            "Could not create hard link from '{}' to '{}'",
+            dest.description(),
+            target.display()
+        )
+    })?;
+    if let Err(e) = filesystem.rename(&temp_path, dest.path()) {
+        let _ = filesystem.remove_file(&temp_path);
+        return Err(e).with_context(|| {
+            format!(
+                "Could not rename '{}' to '{}'",
+                temp_path.display(),
+                dest.description()
+            )
+        });
     }
+    Ok(SyncOutcome::HardLinked)
+}
 
-    #[cfg(windows)]
-    {
-        Ok(outcome)
+fn sync_dir(dest: &Entry, changes: Changes, opts: &SyncOptions) -> Result<SyncOutcome, Error> {
+    if dest.is_dir() {
+        return Ok(SyncOutcome::up_to_date(EntryKind::Directory, changes));
+    }
+    if dest.is_link().is_some() {
+        // Never safe to delete
+        bail!(
+            "Refusing to replace existing path {} by directory",
+            dest.description()
+        );
+    }
+    if !opts.dry_run {
+        dest.filesystem()
+            .create_dir_all(dest.path())
+            .with_context(|| format!("Could not create '{}'", dest.description()))?;
     }
+    Ok(SyncOutcome::DirCreated)
 }
 
 pub fn copy_entry(
     progress_sender: &mpsc::Sender<ProgressMessage>,
+    control: &SyncControl,
     src: &Entry,
     dest: &Entry,
+    changes: Changes,
+    opts: &SyncOptions,
 ) -> Result<SyncOutcome, Error> {
-    let src_path = src.path();
-    let mut src_file = File::open(src_path)
-        .with_context(|| format!("Could not open '{}' for reading", src.description()))?;
+    let size = src.metadata().expect("src_meta should not be None").len();
+    let filesystem = dest.filesystem();
+    if opts.inplace {
+        let mut dest_file = filesystem
+            .create(dest.path())
+            .with_context(|| format!("Could not open '{}' for writing", dest.description()))?;
+        let copied = copy_contents(progress_sender, control, src, dest, &mut *dest_file, opts)
+            .and_then(|()| {
+                dest_file
+                    .flush()
+                    .with_context(|| format!("Could not write to '{}'", dest.description()))
+            });
+        if let Err(e) = copied {
+            // There is no previous version to go back to, and a partially written
+            // file would look like an incomplete copy rather than a cancelled one
+            if control::is_cancellation(&e) {
+                drop(dest_file);
+                let _ = filesystem.remove_file(dest.path());
+            }
+            return Err(e);
+        }
+        return Ok(SyncOutcome::FileCopied { size, changes });
+    }
+
+    // Write to a temporary file first, so that readers of the
+    // destination never see a partially written file
+    let temp_path = temp_path(dest.path());
+    let outcome = filesystem
+        .create(&temp_path)
+        .with_context(|| format!("Could not open '{}' for writing", temp_path.display()))
+        .and_then(|mut temp_file| {
+            // The delta algorithm reads the old destination while the new one is written,
+            // so it is only used with a temporary file
+            let outcome = if opts.delta && dest.is_file() {
+                let stats = patch_contents(progress_sender, control, src, dest, &mut *temp_file)?;
+                SyncOutcome::FilePatched {
+                    size,
+                    literal: stats.literal,
+                    matched: stats.matched,
+                    changes,
+                }
+            } else {
+                copy_contents(progress_sender, control, src, dest, &mut *temp_file, opts)?;
+                SyncOutcome::FileCopied { size, changes }
+            };
+            temp_file
+                .sync_all()
+                .with_context(|| format!("Could not flush '{}'", temp_path.display()))?;
+            Ok(outcome)
+        })
+        .and_then(|outcome| {
+            filesystem
+                .rename(&temp_path, dest.path())
+                .with_context(|| {
+                    format!(
+                        "Could not rename '{}' to '{}'",
+                        temp_path.display(),
+                        dest.description()
+                    )
+                })?;
+            Ok(outcome)
+        });
+    if outcome.is_err() {
+        let _ = filesystem.remove_file(&temp_path);
+    }
+    outcome
+}
+
+/// Copy the contents of `src` to `dest_file`. Between chunks, wait while the
+/// sync is paused, and stop if it is cancelled
+fn copy_contents(
+    progress_sender: &mpsc::Sender<ProgressMessage>,
+    control: &SyncControl,
+    src: &Entry,
+    dest: &Entry,
+    dest_file: &mut dyn FileHandle,
+    opts: &SyncOptions,
+) -> Result<(), Error> {
+    let mut src_file = src
+        .filesystem()
+        .open(src.path())
+        .phase_context(ErrorPhase::Read, || {
+            format!("Could not open '{}' for reading", src.description())
+        })?;
     let src_meta = src.metadata().expect("src_meta should not be None");
     let src_size = src_meta.len();
-    let dest_path = dest.path();
-    let mut dest_file = File::create(dest_path)
-        .with_context(|| format!("Could not open '{}' for writing", dest.description()))?;
+    let send_progress = |done| {
+        let progress = ProgressMessage::Syncing {
+            description: src.description().clone(),
+            size: src_size as usize,
+            done,
+        };
+        let _ = progress_sender.send(progress);
+        control.checkpoint()?;
+        Ok(())
+    };
+
+    #[cfg(target_os = "linux")]
+    {
+        // Files can only be cloned, or copied by the kernel, on the local filesystem
+        let local_files = src_file.as_file().zip(dest_file.as_file());
+        match local_files {
+            Some((src_file, dest_file)) if opts.reflink != ReflinkMode::Never => {
+                match reflink::clone_file(src_file, dest_file) {
+                    Ok(()) => {
+                        // Nothing was transfered for empty files
+                        if src_size > 0 {
+                            send_progress(src_size as usize)?;
+                        }
+                        return Ok(());
+                    }
+                    Err(e) if opts.reflink == ReflinkMode::Always => {
+                        return Err(e).with_context(|| {
+                            format!(
+                                "Could not clone '{}' to '{}'",
+                                src.description(),
+                                dest.description()
+                            )
+                        });
+                    }
+                    Err(_) => {}
+                }
+                // copy_file_range() may fill the holes of sparse files
+                if !opts.sparse {
+                    let done = reflink::copy_file_range(src_file, dest_file, send_progress)
+                        .with_context(|| {
+                            format!(
+                                "Could not copy '{}' to '{}'",
+                                src.description(),
+                                dest.description()
+                            )
+                        })?;
+                    if done {
+                        return Ok(());
+                    }
+                }
+            }
+            None if opts.reflink == ReflinkMode::Always => {
+                bail!("Reflinks are only supported on the local filesystem");
+            }
+            _ => {}
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        if opts.reflink == ReflinkMode::Always {
+            bail!("Reflinks are only supported on Linux");
+        }
+    }
+
+    // Holes can only be found in local files. Other files are copied
+    // through a buffer, zeros included
+    if opts.sparse {
+        if let Some((src_file, dest_file)) = src_file.as_file().zip(dest_file.as_file()) {
+            sparse::copy_sparse(src_file, dest_file, src_size, send_progress).with_context(
+                || {
+                    format!(
+                        "Could not copy '{}' to '{}'",
+                        src.description(),
+                        dest.description()
+                    )
+                },
+            )?;
+            return Ok(());
+        }
+    }
+
+    // Copy whatever is left through a buffer
     let mut buffer = vec![0; BUFFER_SIZE];
     loop {
         let num_read = src_file
             .read(&mut buffer)
-            .with_context(|| format!("Could not read from '{}'", src.description()))?;
+            .phase_context(ErrorPhase::Read, || {
+                format!("Could not read from '{}'", src.description())
+            })?;
         if num_read == 0 {
             break;
         }
         dest_file
             .write_all(&buffer[0..num_read])
             .with_context(|| format!("Could not write to '{}'", dest.description()))?;
+        send_progress(num_read)?;
+    }
+    Ok(())
+}
+
+/// Like copy_contents(), but reuse the blocks of the existing destination
+/// that are also in the source
+fn patch_contents(
+    progress_sender: &mpsc::Sender<ProgressMessage>,
+    control: &SyncControl,
+    src: &Entry,
+    dest: &Entry,
+    dest_file: &mut dyn FileHandle,
+) -> Result<delta::DeltaStats, Error> {
+    let mut src_file = src
+        .filesystem()
+        .open(src.path())
+        .phase_context(ErrorPhase::Read, || {
+            format!("Could not open '{}' for reading", src.description())
+        })?;
+    let src_size = src.metadata().expect("src_meta should not be None").len();
+    let mut basis = dest
+        .filesystem()
+        .open(dest.path())
+        .with_context(|| format!("Could not open '{}' for reading", dest.description()))?;
+    let dest_size = dest.metadata().expect("dest_meta should not be None").len();
+
+    let signature = delta::Signature::compute(&mut basis, delta::block_size(dest_size))
+        .with_context(|| format!("Could not compute signature of '{}'", dest.description()))?;
+    let mut writer = BufWriter::with_capacity(BUFFER_SIZE, dest_file);
+    let on_progress = |done| {
         let progress = ProgressMessage::Syncing {
             description: src.description().clone(),
             size: src_size as usize,
-            done: num_read,
+            done,
         };
         let _ = progress_sender.send(progress);
-    }
-    Ok(SyncOutcome::FileCopied { size: src_size })
+        control.checkpoint()?;
+        Ok(())
+    };
+    let stats = delta::patch(
+        &signature,
+        &mut src_file,
+        &mut basis,
+        &mut writer,
+        on_progress,
+    )
+    .with_context(|| {
+        format!(
+            "Could not patch '{}' with '{}'",
+            dest.description(),
+            src.description()
+        )
+    })?;
+    writer
+        .flush()
+        .with_context(|| format!("Could not write to '{}'", dest.description()))?;
+    Ok(stats)
+}
+
+fn hash_contents(entry: &Entry) -> Result<blake3::Hash, Error> {
+    let mut file = entry
+        .filesystem()
+        .open(entry.path())
+        .phase_context(ErrorPhase::Read, || {
+            format!("Could not open '{}' for hashing", entry.description())
+        })?;
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut file, &mut hasher).phase_context(ErrorPhase::Read, || {
+        format!("Could not read from '{}'", entry.description())
+    })?;
+    Ok(hasher.finalize())
+}
+
+fn has_different_contents(src: &Entry, dest: &Entry) -> Result<bool, Error> {
+    Ok(hash_contents(src)? != hash_contents(dest)?)
 }
 
 fn has_different_size(src: &Entry, dest: &Entry) -> bool {
@@ -170,26 +705,48 @@ fn has_different_size(src: &Entry, dest: &Entry) -> bool {
 
 pub fn sync_entries(
     progress_sender: &mpsc::Sender<ProgressMessage>,
+    control: &SyncControl,
     src: &Entry,
     dest: &Entry,
+    opts: &SyncOptions,
 ) -> Result<SyncOutcome, Error> {
     let _ = progress_sender.send(ProgressMessage::StartSync(src.description().to_string()));
     let is_link = src.is_link().expect("src.is_link should not be None");
+    let mut changes = attribute_changes(src, dest, opts);
     if is_link {
-        return copy_link(src, dest);
+        return copy_link(src, dest, changes, opts);
+    }
+    if src.is_dir() {
+        return sync_dir(dest, changes, opts);
     }
     let different_size = has_different_size(src, dest);
-    let more_recent = is_more_recent_than(src, dest);
-    // TODO: check if files really are different ?
-    if more_recent || different_size {
-        return copy_entry(progress_sender, src, dest);
+    let needs_copy = if opts.checksum && !different_size {
+        // Same size: only the contents can tell us whether the files differ,
+        // no matter what their modification times are
+        changes.contents = has_different_contents(src, dest)?;
+        changes.contents
+    } else if opts.preserve_times {
+        // A destination newer than the source was modified since it was
+        // synced, and may have different contents too
+        different_size || changes.mtime
+    } else {
+        different_size || is_more_recent_than(src, dest)
+    };
+    if !needs_copy {
+        return Ok(SyncOutcome::up_to_date(EntryKind::File, changes));
     }
-    Ok(SyncOutcome::UpToDate)
+    if opts.dry_run {
+        let size = src.metadata().expect("src_meta should not be None").len();
+        return Ok(SyncOutcome::FileCopied { size, changes });
+    }
+    copy_entry(progress_sender, control, src, dest, changes, opts)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::filesystem::LocalFilesystem;
+    use filetime::FileTime;
     use std::sync::mpsc::channel;
     use tempfile::TempDir;
 
@@ -205,7 +762,15 @@ mod tests {
         let dest_entry = Entry::new("dest.txt", dest);
 
         let (progress_output, _) = channel::<ProgressMessage>();
-        sync_entries(&progress_output, &src_entry, &dest_entry).unwrap();
+        let opts = SyncOptions::default();
+        sync_entries(
+            &progress_output,
+            &SyncControl::default(),
+            &src_entry,
+            &dest_entry,
+            &opts,
+        )
+        .unwrap();
 
         let actual = std::fs::read_to_string(dest)?;
         assert_eq!(actual, contents);
@@ -226,12 +791,513 @@ mod tests {
         std::fs::write(dest, old_contents)?;
 
         let (progress_output, _) = channel::<ProgressMessage>();
-        sync_entries(&progress_output, &src_entry, &dest_entry).unwrap();
+        let opts = SyncOptions::default();
+        sync_entries(
+            &progress_output,
+            &SyncControl::default(),
+            &src_entry,
+            &dest_entry,
+            &opts,
+        )
+        .unwrap();
 
         let actual = std::fs::read_to_string(dest)?;
         assert_eq!(actual, new_contents);
         Ok(())
     }
+
+    #[test]
+    fn overwrite_file_leaves_no_temporary_file() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new()?;
+        let tmp_path = tmp_dir.path();
+        let src = &tmp_path.join("src.txt");
+        std::fs::write(src, "new")?;
+        let dest = &tmp_path.join("dest.txt");
+        std::fs::write(dest, "old contents")?;
+        let src_entry = Entry::new("src.txt", src);
+        let dest_entry = Entry::new("dest.txt", dest);
+
+        let (progress_output, _) = channel::<ProgressMessage>();
+        let opts = SyncOptions::default();
+        sync_entries(
+            &progress_output,
+            &SyncControl::default(),
+            &src_entry,
+            &dest_entry,
+            &opts,
+        )
+        .unwrap();
+
+        assert_eq!(std::fs::read_to_string(dest)?, "new");
+        assert!(!temp_path(dest).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn cancelled_copy_leaves_destination_alone() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new()?;
+        let tmp_path = tmp_dir.path();
+        let src = &tmp_path.join("src.txt");
+        std::fs::write(src, "new")?;
+        let src_entry = Entry::new("src.txt", src);
+        let dest = &tmp_path.join("dest.txt");
+        std::fs::write(dest, "old")?;
+        let dest_entry = Entry::new("dest.txt", dest);
+
+        let (progress_output, _) = channel::<ProgressMessage>();
+        let control = SyncControl::default();
+        control.cancel();
+        for inplace in [false, true] {
+            let opts = SyncOptions {
+                inplace,
+                ..Default::default()
+            };
+            let outcome = copy_entry(
+                &progress_output,
+                &control,
+                &src_entry,
+                &dest_entry,
+                Changes::default(),
+                &opts,
+            );
+            assert!(control::is_cancellation(&outcome.unwrap_err()));
+            assert!(!temp_path(dest).exists());
+            if !inplace {
+                assert_eq!(std::fs::read_to_string(dest)?, "old");
+            }
+        }
+        // Written in place, the old contents are gone, so is the partial copy
+        assert!(!dest.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn failed_copy_removes_temporary_file() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new()?;
+        let tmp_path = tmp_dir.path();
+        let src = &tmp_path.join("src.txt");
+        std::fs::write(src, "new")?;
+        let src_entry = Entry::new("src.txt", src);
+        let dest = &tmp_path.join("dest.txt");
+        std::fs::write(dest, "old")?;
+        let dest_entry = Entry::new("dest.txt", dest);
+        std::fs::remove_file(src)?;
+
+        let (progress_output, _) = channel::<ProgressMessage>();
+        let opts = SyncOptions::default();
+        let outcome = copy_entry(
+            &progress_output,
+            &SyncControl::default(),
+            &src_entry,
+            &dest_entry,
+            Changes::default(),
+            &opts,
+        );
+
+        assert!(outcome.is_err());
+        assert_eq!(std::fs::read_to_string(dest)?, "old");
+        assert!(!temp_path(dest).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn remove_leftover_temporary_files() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new()?;
+        let tmp_path = tmp_dir.path();
+        let regular = &tmp_path.join("foo.txt");
+        std::fs::write(regular, "foo")?;
+        let leftover = temp_path(regular);
+        std::fs::write(&leftover, "fo")?;
+
+        remove_temp_files(&LocalFilesystem, tmp_path).unwrap();
+
+        assert!(regular.exists());
+        assert!(!leftover.exists());
+        Ok(())
+    }
+
+    fn created() -> Changes {
+        Changes {
+            created: true,
+            ..Default::default()
+        }
+    }
+
+    fn setup_checksum_test(
+        tmp_path: &Path,
+        src_contents: &str,
+        dest_contents: &str,
+    ) -> Result<(Entry, Entry), std::io::Error> {
+        let src = &tmp_path.join("src.txt");
+        let dest = &tmp_path.join("dest.txt");
+        std::fs::write(dest, dest_contents)?;
+        std::fs::write(src, src_contents)?;
+        // Make sure the source looks more recent than the destination
+        let dest_meta = std::fs::metadata(dest)?;
+        let mtime = FileTime::from_last_modification_time(&dest_meta);
+        let older = FileTime::from_unix_time(mtime.unix_seconds() - 10, 0);
+        filetime::set_file_mtime(dest, older)?;
+        Ok((Entry::new("src.txt", src), Entry::new("dest.txt", dest)))
+    }
+
+    #[test]
+    fn itemize_outcomes() {
+        let changes = Changes {
+            size: true,
+            mtime: true,
+            ..Default::default()
+        };
+        let copied = SyncOutcome::FileCopied { size: 3, changes };
+        assert_eq!(copied.itemize().unwrap(), ">f.st.");
+        let created = SyncOutcome::FileCopied {
+            size: 3,
+            changes: created(),
+        };
+        assert_eq!(created.itemize().unwrap(), ">f++++");
+        let changes = Changes {
+            permissions: true,
+            ..Default::default()
+        };
+        let updated = SyncOutcome::AttributesUpdated {
+            kind: EntryKind::Directory,
+            changes,
+        };
+        assert_eq!(updated.itemize().unwrap(), ".d...p");
+        assert_eq!(SyncOutcome::UpToDate.itemize(), None);
+    }
+
+    #[test]
+    fn copy_when_destination_is_newer() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new()?;
+        let (src_entry, dest_entry) = setup_checksum_test(tmp_dir.path(), "new", "old")?;
+        let dest_meta = std::fs::metadata(dest_entry.path())?;
+        let mtime = FileTime::from_last_modification_time(&dest_meta);
+        let newer = FileTime::from_unix_time(mtime.unix_seconds() + 20, 0);
+        filetime::set_file_mtime(dest_entry.path(), newer)?;
+        let dest_entry = Entry::new("dest.txt", dest_entry.path());
+
+        let (progress_output, _) = channel::<ProgressMessage>();
+        let outcome = sync_entries(
+            &progress_output,
+            &SyncControl::default(),
+            &src_entry,
+            &dest_entry,
+            &SyncOptions::default(),
+        )
+        .unwrap();
+
+        let changes = Changes {
+            mtime: true,
+            ..Default::default()
+        };
+        assert_eq!(outcome, SyncOutcome::FileCopied { size: 3, changes });
+        assert_eq!(std::fs::read_to_string(dest_entry.path())?, "new");
+        Ok(())
+    }
+
+    #[test]
+    fn checksum_skips_same_contents() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new()?;
+        let (src_entry, dest_entry) = setup_checksum_test(tmp_dir.path(), "same", "same")?;
+
+        let (progress_output, _) = channel::<ProgressMessage>();
+        let opts = SyncOptions {
+            checksum: true,
+            // The files were written at different times
+            preserve_times: false,
+            ..Default::default()
+        };
+        let outcome = sync_entries(
+            &progress_output,
+            &SyncControl::default(),
+            &src_entry,
+            &dest_entry,
+            &opts,
+        )
+        .unwrap();
+
+        assert_eq!(outcome, SyncOutcome::UpToDate);
+        Ok(())
+    }
+
+    #[test]
+    fn checksum_copies_different_contents_with_same_size() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new()?;
+        let (src_entry, dest_entry) = setup_checksum_test(tmp_dir.path(), "new", "old")?;
+
+        let (progress_output, _) = channel::<ProgressMessage>();
+        let opts = SyncOptions {
+            checksum: true,
+            ..Default::default()
+        };
+        let outcome = sync_entries(
+            &progress_output,
+            &SyncControl::default(),
+            &src_entry,
+            &dest_entry,
+            &opts,
+        )
+        .unwrap();
+
+        match outcome {
+            SyncOutcome::FileCopied { size, changes } => {
+                assert_eq!(size, 3);
+                assert!(changes.contents);
+                assert!(!changes.size);
+            }
+            _ => panic!("Expected FileCopied, got {:?}", outcome),
+        }
+        let actual = std::fs::read_to_string(dest_entry.path())?;
+        assert_eq!(actual, "new");
+        Ok(())
+    }
+
+    #[test]
+    fn dry_run_does_not_write() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new()?;
+        let tmp_path = tmp_dir.path();
+        let src = &tmp_path.join("src.txt");
+        std::fs::write(src, "some contents")?;
+        let src_entry = Entry::new("src.txt", src);
+        let dest = &tmp_path.join("dest.txt");
+        let dest_entry = Entry::new("dest.txt", dest);
+
+        let (progress_output, _) = channel::<ProgressMessage>();
+        let opts = SyncOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let outcome = sync_entries(
+            &progress_output,
+            &SyncControl::default(),
+            &src_entry,
+            &dest_entry,
+            &opts,
+        )
+        .unwrap();
+
+        assert_eq!(
+            outcome,
+            SyncOutcome::FileCopied {
+                size: 13,
+                changes: created()
+            }
+        );
+        assert!(!dest.exists());
+        Ok(())
+    }
+
+    fn copy_with_reflink_mode(reflink: ReflinkMode) -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new()?;
+        let tmp_path = tmp_dir.path();
+        let src = &tmp_path.join("src.bin");
+        let contents: Vec<u8> = (0..10_000_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(src, &contents)?;
+        let src_entry = Entry::new("src.bin", src);
+        let dest = &tmp_path.join("dest.bin");
+        let dest_entry = Entry::new("dest.bin", dest);
+
+        let (progress_output, progress_input) = channel::<ProgressMessage>();
+        let opts = SyncOptions {
+            reflink,
+            ..Default::default()
+        };
+        let outcome = sync_entries(
+            &progress_output,
+            &SyncControl::default(),
+            &src_entry,
+            &dest_entry,
+            &opts,
+        )
+        .unwrap();
+        drop(progress_output);
+
+        assert_eq!(
+            outcome,
+            SyncOutcome::FileCopied {
+                size: 10_000_000,
+                changes: created()
+            }
+        );
+        assert!(std::fs::read(dest)? == contents);
+        let done: usize = progress_input
+            .iter()
+            .map(|message| match message {
+                ProgressMessage::Syncing { done, .. } => done,
+                _ => 0,
+            })
+            .sum();
+        assert_eq!(done, 10_000_000);
+        Ok(())
+    }
+
+    #[test]
+    fn copy_with_fast_paths() -> Result<(), std::io::Error> {
+        copy_with_reflink_mode(ReflinkMode::Auto)
+    }
+
+    #[test]
+    fn copy_through_buffer() -> Result<(), std::io::Error> {
+        copy_with_reflink_mode(ReflinkMode::Never)
+    }
+
+    #[test]
+    fn no_progress_for_empty_files() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new()?;
+        let tmp_path = tmp_dir.path();
+        let src = &tmp_path.join("empty.txt");
+        std::fs::write(src, "")?;
+        let src_entry = Entry::new("empty.txt", src);
+        let dest_entry = Entry::new("dest.txt", &tmp_path.join("dest.txt"));
+
+        let (progress_output, progress_input) = channel::<ProgressMessage>();
+        sync_entries(
+            &progress_output,
+            &SyncControl::default(),
+            &src_entry,
+            &dest_entry,
+            &SyncOptions::default(),
+        )
+        .unwrap();
+        drop(progress_output);
+
+        let syncing = progress_input
+            .iter()
+            .filter(|message| matches!(message, ProgressMessage::Syncing { .. }))
+            .count();
+        assert_eq!(syncing, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn delta_reuses_blocks_of_existing_file() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new()?;
+        let old: String = (0..10_000).map(|i| format!("line {}\n", i)).collect();
+        let new = old.replacen("line 5000\n", "changed line\n", 1);
+        let (src_entry, dest_entry) = setup_checksum_test(tmp_dir.path(), &new, &old)?;
+
+        let (progress_output, _) = channel::<ProgressMessage>();
+        let opts = SyncOptions {
+            delta: true,
+            ..Default::default()
+        };
+        let outcome = sync_entries(
+            &progress_output,
+            &SyncControl::default(),
+            &src_entry,
+            &dest_entry,
+            &opts,
+        )
+        .unwrap();
+
+        let actual = std::fs::read_to_string(dest_entry.path())?;
+        assert_eq!(actual, new);
+        match outcome {
+            SyncOutcome::FilePatched {
+                size,
+                literal,
+                matched,
+                changes,
+            } => {
+                assert!(changes.size);
+                assert_eq!(size, new.len() as u64);
+                assert_eq!(literal + matched, size);
+                assert!(literal < size / 10);
+            }
+            _ => panic!("Expected FilePatched, got {:?}", outcome),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn delta_is_not_used_without_existing_file() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new()?;
+        let tmp_path = tmp_dir.path();
+        let src = &tmp_path.join("src.txt");
+        std::fs::write(src, "some contents")?;
+        let src_entry = Entry::new("src.txt", src);
+        let dest = &tmp_path.join("dest.txt");
+        let dest_entry = Entry::new("dest.txt", dest);
+
+        let (progress_output, _) = channel::<ProgressMessage>();
+        let opts = SyncOptions {
+            delta: true,
+            ..Default::default()
+        };
+        let outcome = sync_entries(
+            &progress_output,
+            &SyncControl::default(),
+            &src_entry,
+            &dest_entry,
+            &opts,
+        )
+        .unwrap();
+
+        assert_eq!(
+            outcome,
+            SyncOutcome::FileCopied {
+                size: 13,
+                changes: created()
+            }
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod dir_tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+    use tempfile::TempDir;
+
+    fn sync_dir_entries(tmp_path: &Path, dest: &str) -> Result<SyncOutcome, Error> {
+        let src = &tmp_path.join("src");
+        std::fs::create_dir_all(src)?;
+        let src_entry = Entry::new("src", src);
+        let dest_entry = Entry::new(dest, &tmp_path.join(dest));
+        let (progress_output, _) = channel::<ProgressMessage>();
+        sync_entries(
+            &progress_output,
+            &SyncControl::default(),
+            &src_entry,
+            &dest_entry,
+            &SyncOptions::default(),
+        )
+    }
+
+    #[test]
+    fn create_missing_dir() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new()?;
+        let tmp_path = tmp_dir.path();
+
+        let outcome = sync_dir_entries(tmp_path, "dest");
+        assert_eq!(outcome.unwrap(), SyncOutcome::DirCreated);
+        assert!(tmp_path.join("dest").is_dir());
+        Ok(())
+    }
+
+    #[test]
+    fn existing_dir_is_up_to_date() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new()?;
+        let tmp_path = tmp_dir.path();
+        std::fs::create_dir(tmp_path.join("dest"))?;
+
+        let outcome = sync_dir_entries(tmp_path, "dest");
+        assert_eq!(outcome.unwrap(), SyncOutcome::UpToDate);
+        Ok(())
+    }
+
+    #[test]
+    fn refuse_to_replace_file_by_dir() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new()?;
+        let tmp_path = tmp_dir.path();
+        std::fs::write(tmp_path.join("dest"), "")?;
+
+        let outcome = sync_dir_entries(tmp_path, "dest");
+        assert!(outcome.is_err());
+        assert!(tmp_path.join("dest").is_file());
+        Ok(())
+    }
 }
 
 #[cfg(unix)]
@@ -270,7 +1336,12 @@ mod symlink_tests {
         let src_entry = Entry::new("src", src_link);
         let dest_path = &tmp_path.join(dest);
         let dest_entry = Entry::new(dest, dest_path);
-        copy_link(&src_entry, &dest_entry)
+        copy_link(
+            &src_entry,
+            &dest_entry,
+            Changes::default(),
+            &SyncOptions::default(),
+        )
     }
 
     #[test]
@@ -315,6 +1386,26 @@ mod symlink_tests {
         Ok(())
     }
 
+    #[test]
+    fn dry_run_does_not_update_link() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new()?;
+        let tmp_path = tmp_dir.path();
+        let src_link = setup_sync_link_test(tmp_path)?;
+
+        let existing_link = tmp_path.join("existing_link");
+        create_link("old", &existing_link)?;
+        let src_entry = Entry::new("src", &src_link);
+        let dest_entry = Entry::new("existing_link", &existing_link);
+        let opts = SyncOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        let outcome = copy_link(&src_entry, &dest_entry, Changes::default(), &opts);
+        assert_eq!(outcome.unwrap(), SyncOutcome::SymlinkUpdated);
+        assert_links_to(tmp_path, "existing_link", "old");
+        Ok(())
+    }
+
     #[test]
     fn create_link_dest_is_a_regular_file() -> Result<(), std::io::Error> {
         let tmp_dir = TempDir::new()?;
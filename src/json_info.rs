@@ -0,0 +1,248 @@
+//! json_info
+//!
+//! Report transfer progress as newline-delimited JSON, for other programs to consume
+
+use std::io;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Map, Value};
+
+use crate::error::SyncError;
+use crate::progress::{Progress, ProgressInfo, SyncOutcome};
+use crate::sync::Stats;
+
+/// Writes one JSON object per line for each event. Every object has an
+/// `event` field, which is one of `start`, `synced`, `progress`, `error`
+/// and `end`
+pub struct JsonProgressInfo {
+    writer: Box<dyn Write + Send>,
+    progress_interval: Duration,
+    last_progress: Option<Instant>,
+}
+
+impl JsonProgressInfo {
+    /// Write events to stdout
+    pub fn new() -> Self {
+        Self::with_writer(Box::new(io::stdout()))
+    }
+
+    pub fn with_writer(writer: Box<dyn Write + Send>) -> Self {
+        Self {
+            writer,
+            progress_interval: Duration::from_secs(1),
+            last_progress: None,
+        }
+    }
+
+    /// Emit at most one `progress` event per `interval` (one second by default)
+    pub fn set_progress_interval(&mut self, interval: Duration) {
+        self.progress_interval = interval;
+    }
+
+    fn emit(&mut self, event: &str, value: Value) {
+        // The event name comes first, to make the output easier to read
+        let mut object = Map::new();
+        object.insert("event".to_string(), json!(event));
+        if let Value::Object(fields) = value {
+            object.extend(fields);
+        }
+        // Ignoring errors when writing progress, like the console does
+        let _ = writeln!(self.writer, "{}", Value::Object(object));
+        let _ = self.writer.flush();
+    }
+}
+
+impl Default for JsonProgressInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressInfo for JsonProgressInfo {
+    fn start(&mut self, source: &str, destination: &str) {
+        self.emit(
+            "start",
+            json!({
+                "source": source,
+                "destination": destination,
+            }),
+        );
+    }
+
+    fn synced(&mut self, name: &str, outcome: &SyncOutcome) {
+        let mut value = json!({ "entry": name });
+        if let (Some(fields), Value::Object(outcome)) =
+            (value.as_object_mut(), outcome_to_json(outcome))
+        {
+            fields.extend(outcome);
+        }
+        self.emit("synced", value);
+    }
+
+    fn progress(&mut self, progress: &Progress) {
+        let now = Instant::now();
+        if let Some(last_progress) = self.last_progress {
+            if now.duration_since(last_progress) < self.progress_interval {
+                return;
+            }
+        }
+        self.last_progress = Some(now);
+        self.emit(
+            "progress",
+            json!({
+                "current_file": progress.current_file,
+                "file_done": progress.file_done,
+                "file_size": progress.file_size,
+                "total_done": progress.total_done,
+                "total_size": progress.total_size,
+                "index": progress.index,
+                "num_files": progress.num_files,
+                "eta": progress.eta,
+            }),
+        );
+    }
+
+    fn error(&mut self, error: &SyncError) {
+        self.emit("error", error.to_json());
+    }
+
+    fn end(&mut self, stats: &Stats) {
+        self.emit(
+            "end",
+            json!({
+                "num_files": stats.num_files,
+                "total_size": stats.total_size,
+                "total_transfered": stats.total_transfered,
+                "literal_bytes": stats.literal_bytes,
+                "matched_bytes": stats.matched_bytes,
+                "num_synced": stats.num_synced,
+                "up_to_date": stats.up_to_date,
+                "copied": stats.copied,
+                "errors": stats.errors,
+                "symlink_created": stats.symlink_created,
+                "symlink_updated": stats.symlink_updated,
+                "dirs_created": stats.dirs_created,
+                "hard_linked": stats.hard_linked,
+                "deleted": stats.deleted,
+                "cancelled": stats.cancelled,
+                "duration_ms": stats.duration().as_millis() as u64,
+            }),
+        );
+    }
+}
+
+fn outcome_to_json(outcome: &SyncOutcome) -> Value {
+    let details = match outcome {
+        SyncOutcome::AttributesUpdated { kind, changes } => json!({
+            "kind": kind.as_str(),
+            "changes": changes.names(),
+        }),
+        SyncOutcome::FileCopied { size, changes } => json!({
+            "size": size,
+            "changes": changes.names(),
+        }),
+        SyncOutcome::FilePatched {
+            size,
+            literal,
+            matched,
+            changes,
+        } => json!({
+            "size": size,
+            "literal": literal,
+            "matched": matched,
+            "changes": changes.names(),
+        }),
+        _ => json!({}),
+    };
+    let mut value = json!({ "outcome": outcome.name() });
+    if let (Some(fields), Value::Object(details)) = (value.as_object_mut(), details) {
+        fields.extend(details);
+    }
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::Changes;
+    use std::sync::{Arc, Mutex};
+
+    /// Shares the written bytes with the test
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedBuffer {
+        fn events(&self) -> Vec<Value> {
+            let contents = self.0.lock().unwrap();
+            String::from_utf8_lossy(&contents)
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect()
+        }
+    }
+
+    fn progress(file_done: usize) -> Progress {
+        Progress {
+            current_file: "foo.txt".to_string(),
+            file_done,
+            file_size: 100,
+            total_done: file_done,
+            total_size: 100,
+            index: 1,
+            num_files: 1,
+            eta: 0,
+        }
+    }
+
+    #[test]
+    fn one_object_per_event() {
+        let buffer = SharedBuffer::default();
+        let mut info = JsonProgressInfo::with_writer(Box::new(buffer.clone()));
+        info.start("src", "dest");
+        let changes = Changes {
+            created: true,
+            ..Default::default()
+        };
+        info.synced("foo.txt", &SyncOutcome::FileCopied { size: 3, changes });
+        info.end(&Stats::new());
+
+        let events = buffer.events();
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[0]["event"], "start");
+        assert_eq!(events[0]["source"], "src");
+        assert_eq!(events[1]["event"], "synced");
+        assert_eq!(events[1]["entry"], "foo.txt");
+        assert_eq!(events[1]["outcome"], "copied");
+        assert_eq!(events[1]["size"], 3);
+        assert_eq!(events[1]["changes"], json!(["created"]));
+        assert_eq!(events[2]["event"], "end");
+        assert_eq!(events[2]["errors"], 0);
+    }
+
+    #[test]
+    fn progress_is_throttled() {
+        let buffer = SharedBuffer::default();
+        let mut info = JsonProgressInfo::with_writer(Box::new(buffer.clone()));
+        info.set_progress_interval(Duration::from_secs(3600));
+        info.progress(&progress(10));
+        info.progress(&progress(20));
+        info.set_progress_interval(Duration::ZERO);
+        info.progress(&progress(30));
+
+        let events = buffer.events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["file_done"], 10);
+        assert_eq!(events[1]["file_done"], 30);
+    }
+}
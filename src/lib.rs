@@ -5,6 +5,9 @@
 //! To use rusync as a library, start with the [Syncer](sync/struct.Syncer.html) struct.
 //!
 //! To customize its output, implement the [ProgressInfo](progress/trait.ProgressInfo.html) trait.
+//!
+//! To sync somewhere else than on the local filesystem, implement the
+//! [Filesystem](filesystem/trait.Filesystem.html) trait.
 
 //! # Example
 //!
@@ -15,8 +18,10 @@
 //! let options = rusync::SyncOptions::default();
 //!
 //!
+//! # let tmp_dir = tempfile::TempDir::new().unwrap();
 //! let source = std::path::Path::new("src");
 //! let destination = std::path::Path::new("dest");
+//! # let destination = tmp_dir.path().join(destination);
 //! let syncer = rusync::Syncer::new(&source, &destination, options, Box::new(console_info));
 //! let stats = syncer.sync();
 //! match stats {
@@ -30,12 +35,36 @@
 //! ```
 //!
 pub mod console_info;
+pub mod control;
+mod delta;
 mod entry;
+pub mod error;
+pub mod filesystem;
+pub mod filter;
 mod fsops;
+pub mod json_info;
+pub mod log_info;
+pub mod ownership;
+pub mod plan;
 pub mod progress;
+#[cfg(target_os = "linux")]
+mod reflink;
+mod sparse;
 pub mod sync;
 mod workers;
+#[cfg(unix)]
+mod xattrs;
 pub use crate::console_info::ConsoleProgressInfo;
+pub use crate::control::SyncHandle;
+pub use crate::error::{ErrorListFormat, ErrorListProgressInfo, ErrorPhase, SyncError};
+pub use crate::filesystem::{Filesystem, LocalFilesystem, MemoryFilesystem};
+pub use crate::filter::FilterRule;
+pub use crate::json_info::JsonProgressInfo;
+pub use crate::log_info::{LogProgressInfo, OutFormat};
+pub use crate::ownership::IdMapping;
+pub use crate::plan::{Action, PlannedAction, SyncPlan};
+pub use crate::progress::{Changes, EntryKind, SyncOutcome, TeeProgressInfo};
+pub use crate::sync::ReflinkMode;
 pub use crate::sync::Stats;
 pub use crate::sync::SyncOptions;
 pub use crate::sync::Syncer;
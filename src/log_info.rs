@@ -0,0 +1,331 @@
+//! log_info
+//!
+//! Keep a record of the synced entries in a log file
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Error};
+
+use crate::error::SyncError;
+use crate::filesystem::{Filesystem, LocalFilesystem, Metadata};
+use crate::progress::{ProgressInfo, SyncOutcome};
+use crate::sync::Stats;
+
+/// Format used when none is given: timestamp, itemized changes and path
+pub const DEFAULT_OUT_FORMAT: &str = "%t %i %n";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Literal(String),
+    /// %n
+    Path,
+    /// %l
+    Size,
+    /// %b
+    Transferred,
+    /// %o
+    Outcome,
+    /// %i
+    Itemized,
+    /// %t
+    Timestamp,
+    /// %B
+    Mode,
+}
+
+/// A template for the line written for each entry, where placeholders
+/// starting with `%` are replaced by details about the entry:
+///
+/// * `%n`: path of the entry, relative to the destination
+/// * `%l`: size of the file, in bytes
+/// * `%b`: number of bytes written from the source
+/// * `%o`: outcome, such as `copied` or `deleted`
+/// * `%i`: itemized changes, see `SyncOutcome::itemize()`
+/// * `%t`: date and time, in RFC 3339 format
+/// * `%B`: permissions of the entry in the destination, such as `rw-r--r--`
+/// * `%%`: a literal `%`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutFormat {
+    tokens: Vec<Token>,
+}
+
+impl OutFormat {
+    pub fn parse(template: &str) -> Result<Self, Error> {
+        let mut tokens = vec![];
+        let mut literal = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                literal.push(c);
+                continue;
+            }
+            let token = match chars.next() {
+                Some('%') => {
+                    literal.push('%');
+                    continue;
+                }
+                Some('n') => Token::Path,
+                Some('l') => Token::Size,
+                Some('b') => Token::Transferred,
+                Some('o') => Token::Outcome,
+                Some('i') => Token::Itemized,
+                Some('t') => Token::Timestamp,
+                Some('B') => Token::Mode,
+                Some(other) => bail!("Unknown placeholder '%{}' in '{}'", other, template),
+                None => bail!("Unterminated placeholder in '{}'", template),
+            };
+            if !literal.is_empty() {
+                tokens.push(Token::Literal(std::mem::take(&mut literal)));
+            }
+            tokens.push(token);
+        }
+        if !literal.is_empty() {
+            tokens.push(Token::Literal(literal));
+        }
+        Ok(Self { tokens })
+    }
+
+    /// Format the line for the entry at `rel_path`, whose metadata in the
+    /// destination is `metadata`, if it still exists
+    fn format(
+        &self,
+        rel_path: &str,
+        outcome: &SyncOutcome,
+        metadata: Option<&Metadata>,
+        timestamp: SystemTime,
+    ) -> String {
+        let mut line = String::new();
+        for token in &self.tokens {
+            match token {
+                Token::Literal(literal) => line.push_str(literal),
+                Token::Path => line.push_str(rel_path),
+                Token::Size => {
+                    let size = match outcome {
+                        SyncOutcome::FileCopied { size, .. }
+                        | SyncOutcome::FilePatched { size, .. } => *size,
+                        _ => metadata.filter(|m| m.is_file()).map_or(0, |m| m.len()),
+                    };
+                    line.push_str(&size.to_string());
+                }
+                Token::Transferred => {
+                    let transferred = match outcome {
+                        SyncOutcome::FileCopied { size, .. } => *size,
+                        SyncOutcome::FilePatched { literal, .. } => *literal,
+                        _ => 0,
+                    };
+                    line.push_str(&transferred.to_string());
+                }
+                Token::Outcome => line.push_str(outcome.name()),
+                Token::Itemized => {
+                    let itemized = outcome.itemize().unwrap_or_default();
+                    line.push_str(&format!("{:<9}", itemized));
+                }
+                Token::Timestamp => {
+                    let timestamp = humantime::format_rfc3339_seconds(timestamp);
+                    line.push_str(&timestamp.to_string());
+                }
+                Token::Mode => line.push_str(&format_mode(metadata)),
+            }
+        }
+        line
+    }
+}
+
+impl FromStr for OutFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+impl Default for OutFormat {
+    fn default() -> Self {
+        Self::parse(DEFAULT_OUT_FORMAT).expect("default format should be valid")
+    }
+}
+
+fn format_mode(metadata: Option<&Metadata>) -> String {
+    let metadata = match metadata {
+        Some(metadata) => metadata,
+        None => return "-".repeat(9),
+    };
+    "rwxrwxrwx"
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if metadata.mode & (0o400 >> i) != 0 {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// Appends one line for each entry that was changed to a log file, along with
+/// the errors and a summary at the end of the sync. Entries that were already up
+/// to date are not logged
+pub struct LogProgressInfo {
+    writer: Box<dyn Write + Send>,
+    format: OutFormat,
+    destination: PathBuf,
+    filesystem: Arc<dyn Filesystem>,
+}
+
+impl LogProgressInfo {
+    /// Append to the log file at `path`, creating it if needed
+    pub fn new(path: &Path, format: OutFormat) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Could not open log file at '{}'", path.display()))?;
+        Ok(Self::with_writer(Box::new(file), format))
+    }
+
+    pub fn with_writer(writer: Box<dyn Write + Send>, format: OutFormat) -> Self {
+        Self {
+            writer,
+            format,
+            destination: PathBuf::new(),
+            filesystem: Arc::new(LocalFilesystem::new()),
+        }
+    }
+
+    fn write_line(&mut self, line: &str) {
+        // Ignoring errors when trying to log, like the console does
+        let _ = writeln!(self.writer, "{}", line);
+        let _ = self.writer.flush();
+    }
+}
+
+fn now() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+}
+
+impl ProgressInfo for LogProgressInfo {
+    fn destination_filesystem(&mut self, filesystem: &Arc<dyn Filesystem>) {
+        self.filesystem = filesystem.clone();
+    }
+
+    fn start(&mut self, source: &str, destination: &str) {
+        self.destination = PathBuf::from(destination);
+        let line = format!("{} Syncing from {} to {}", now(), source, destination);
+        self.write_line(&line);
+    }
+
+    fn synced(&mut self, name: &str, outcome: &SyncOutcome) {
+        if *outcome == SyncOutcome::UpToDate {
+            return;
+        }
+        let metadata = self
+            .filesystem
+            .symlink_metadata(&self.destination.join(name))
+            .ok();
+        let line = self
+            .format
+            .format(name, outcome, metadata.as_ref(), SystemTime::now());
+        self.write_line(&line);
+    }
+
+    fn error(&mut self, error: &SyncError) {
+        let line = format!(
+            "{} Error ({}) {}: {}",
+            now(),
+            error.phase,
+            error.entry,
+            error.details
+        );
+        self.write_line(&line);
+    }
+
+    fn end(&mut self, stats: &Stats) {
+        let line = format!(
+            "{} Synced {} files ({} up to date, {} copied, {} deleted), {} bytes transferred, {} errors",
+            now(),
+            stats.num_synced,
+            stats.up_to_date,
+            stats.copied,
+            stats.deleted,
+            stats.total_transfered,
+            stats.errors
+        );
+        self.write_line(&line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFilesystem;
+    use crate::progress::Changes;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn copied() -> SyncOutcome {
+        SyncOutcome::FileCopied {
+            size: 42,
+            changes: Changes {
+                created: true,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn parse_and_format() {
+        let format = OutFormat::parse("[%t] %o %n %l/%b 100%%").unwrap();
+        let timestamp = UNIX_EPOCH + Duration::from_secs(86400);
+        let line = format.format("a/b.txt", &copied(), None, timestamp);
+        assert_eq!(line, "[1970-01-02T00:00:00Z] copied a/b.txt 42/42 100%");
+    }
+
+    #[test]
+    fn itemized_changes_are_aligned() {
+        let format = OutFormat::parse("%i|").unwrap();
+        let line = format.format("foo", &copied(), None, UNIX_EPOCH);
+        assert_eq!(line, ">f++++   |");
+    }
+
+    #[test]
+    fn invalid_placeholders() {
+        assert!(OutFormat::parse("%n %z").is_err());
+        assert!(OutFormat::parse("%n %").is_err());
+    }
+
+    #[test]
+    fn format_permissions() -> Result<(), std::io::Error> {
+        let filesystem = MemoryFilesystem::new();
+        filesystem.write("/dest/foo", "foo")?;
+        filesystem.set_permissions(Path::new("/dest/foo"), 0o640)?;
+        let metadata = filesystem.metadata(Path::new("/dest/foo"))?;
+        let format = OutFormat::parse("%B").unwrap();
+        let line = format.format("foo", &copied(), Some(&metadata), UNIX_EPOCH);
+        assert_eq!(line, "rw-r-----");
+        Ok(())
+    }
+
+    #[test]
+    fn read_metadata_in_destination_filesystem() -> Result<(), std::io::Error> {
+        let filesystem = MemoryFilesystem::new();
+        filesystem.write("/dest/foo", "foo")?;
+        filesystem.set_permissions(Path::new("/dest/foo"), 0o600)?;
+        let tmp_dir = tempfile::TempDir::new()?;
+        let log_path = tmp_dir.path().join("rusync.log");
+        let format = OutFormat::parse("%n %l %B").unwrap();
+        let mut log_info = LogProgressInfo::new(&log_path, format).unwrap();
+
+        log_info.destination_filesystem(&(Arc::new(filesystem) as Arc<dyn Filesystem>));
+        log_info.start("/src", "/dest");
+        log_info.synced("foo", &SyncOutcome::HardLinked);
+
+        let contents = std::fs::read_to_string(&log_path)?;
+        assert!(contents.ends_with("foo 3 rw-------\n"), "{}", contents);
+        Ok(())
+    }
+}
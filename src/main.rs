@@ -1,10 +1,39 @@
-use anyhow::Error;
-use clap::Parser;
+use anyhow::{anyhow, Error};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser};
 use rusync::console_info::ConsoleProgressInfo;
+use rusync::log_info::DEFAULT_OUT_FORMAT;
+use rusync::progress::TeeProgressInfo;
 use rusync::sync::SyncOptions;
+use rusync::ErrorListFormat;
+use rusync::ErrorListProgressInfo;
+use rusync::FilterRule;
+use rusync::IdMapping;
+use rusync::JsonProgressInfo;
+use rusync::LogProgressInfo;
+use rusync::OutFormat;
+use rusync::ReflinkMode;
 use rusync::Syncer;
 use std::path::PathBuf;
 use std::process;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(anyhow!("Invalid output format: '{}'", s)),
+        }
+    }
+}
 
 #[derive(Debug, Parser)]
 #[clap(name = "rusync")]
@@ -15,33 +44,264 @@ struct Opt {
     )]
     no_preserve_permissions: bool,
 
-    #[clap(long = "err-list", help = "Write errors to the given file")]
+    #[clap(
+        long = "no-times",
+        help = "Do not preserve access and modification times"
+    )]
+    no_preserve_times: bool,
+
+    #[clap(
+        long = "inplace",
+        help = "Write directly to destination files instead of using temporary files"
+    )]
+    inplace: bool,
+
+    #[clap(
+        long = "delete",
+        help = "Delete entries from the destination that do not exist in the source"
+    )]
+    delete: bool,
+
+    #[clap(
+        long = "dry-run",
+        help = "Show what would be done without changing the destination"
+    )]
+    dry_run: bool,
+
+    #[clap(
+        long = "checksum",
+        help = "Compare contents of files with the same size instead of their modification times"
+    )]
+    checksum: bool,
+
+    #[clap(
+        long = "delta",
+        help = "Only write the parts of existing destination files that changed"
+    )]
+    delta: bool,
+
+    #[clap(
+        long = "hard-links",
+        help = "Preserve hard links between source files (no-op on Windows)"
+    )]
+    hard_links: bool,
+
+    #[clap(
+        long = "reflink",
+        value_name = "WHEN",
+        default_value = "auto",
+        possible_values = ["auto", "always", "never"],
+        help = "Clone files instead of copying their data, on filesystems supporting it"
+    )]
+    reflink: ReflinkMode,
+
+    #[clap(
+        long = "sparse",
+        help = "Recreate holes of sparse files in the destination"
+    )]
+    sparse: bool,
+
+    #[clap(
+        long = "xattrs",
+        help = "Preserve extended attributes (no-op on Windows)"
+    )]
+    xattrs: bool,
+
+    #[clap(long = "acls", help = "Preserve POSIX ACLs (no-op on Windows)")]
+    acls: bool,
+
+    #[clap(
+        short = 'o',
+        long = "owner",
+        help = "Preserve owners (usually requires root, no-op on Windows)"
+    )]
+    owner: bool,
+
+    #[clap(
+        short = 'g',
+        long = "group",
+        help = "Preserve groups (no-op on Windows)"
+    )]
+    group: bool,
+
+    #[clap(long = "numeric-ids", help = "Never look up user and group names")]
+    numeric_ids: bool,
+
+    #[clap(
+        long = "usermap",
+        value_name = "FROM:TO",
+        use_value_delimiter = true,
+        requires = "owner",
+        help = "Translate owners, by name or numeric id. FROM can be '*'"
+    )]
+    usermap: Vec<IdMapping>,
+
+    #[clap(
+        long = "groupmap",
+        value_name = "FROM:TO",
+        use_value_delimiter = true,
+        requires = "group",
+        help = "Translate groups, by name or numeric id. FROM can be '*'"
+    )]
+    groupmap: Vec<IdMapping>,
+
+    #[clap(
+        long = "include",
+        value_name = "PATTERN",
+        help = "Include entries matching the pattern, even if they match a later --exclude"
+    )]
+    include: Vec<String>,
+
+    #[clap(
+        long = "exclude",
+        value_name = "PATTERN",
+        help = "Exclude entries matching the pattern. Excluded directories are not walked"
+    )]
+    exclude: Vec<String>,
+
+    #[clap(
+        long = "ignore-files",
+        help = "Skip entries matched by .gitignore, .ignore and .rusyncignore files"
+    )]
+    ignore_files: bool,
+
+    #[clap(
+        short = 'j',
+        long = "jobs",
+        default_value = "1",
+        help = "Number of entries to sync in parallel"
+    )]
+    jobs: usize,
+
+    #[clap(
+        long = "output",
+        value_name = "FORMAT",
+        default_value = "text",
+        possible_values = ["text", "json"],
+        help = "Print progress for humans, or as one JSON object per line"
+    )]
+    output: OutputFormat,
+
+    #[clap(
+        short = 'i',
+        long = "itemize",
+        help = "Display what changed for each synced entry"
+    )]
+    itemize: bool,
+
+    #[clap(
+        long = "log-file",
+        value_name = "FILE",
+        help = "Append a line for each changed entry to the given file"
+    )]
+    log_file: Option<PathBuf>,
+
+    #[clap(
+        long = "out-format",
+        value_name = "FORMAT",
+        default_value = DEFAULT_OUT_FORMAT,
+        requires = "log-file",
+        help = "Format of the lines written to the log file. Placeholders: %n (path), %l (size), \
+                %b (bytes transferred), %o (outcome), %i (itemized changes), %t (date and time), \
+                %B (permissions)"
+    )]
+    out_format: OutFormat,
+
+    #[clap(
+        long = "err-list",
+        help = "Write errors to the given file, replacing its contents"
+    )]
     error_list_path: Option<PathBuf>,
 
-    #[clap(parse(from_os_str))]
-    source: PathBuf,
+    #[clap(
+        long = "err-format",
+        value_name = "FORMAT",
+        default_value = "names",
+        possible_values = ["names", "tsv", "json"],
+        requires = "error-list-path",
+        help = "Format of the error list: entry names, or entries along with the reasons \
+                of the errors, as tab-separated values or JSON lines"
+    )]
+    error_list_format: ErrorListFormat,
+
+    #[clap(
+        parse(from_os_str),
+        required = true,
+        help = "Files and directories to sync. A trailing slash syncs the contents of a directory \
+                instead of the directory itself"
+    )]
+    sources: Vec<PathBuf>,
 
     #[clap(parse(from_os_str))]
     destination: PathBuf,
 }
 
+/// Build the list of filter rules, in the order they were given on the command line
+fn filter_rules(matches: &ArgMatches, opt: &Opt) -> Vec<FilterRule> {
+    let includes = opt
+        .include
+        .iter()
+        .map(|p| FilterRule::Include(p.to_string()));
+    let excludes = opt
+        .exclude
+        .iter()
+        .map(|p| FilterRule::Exclude(p.to_string()));
+    let include_indices = matches.indices_of("include").into_iter().flatten();
+    let exclude_indices = matches.indices_of("exclude").into_iter().flatten();
+    let mut rules: Vec<_> = include_indices
+        .zip(includes)
+        .chain(exclude_indices.zip(excludes))
+        .collect();
+    rules.sort_by_key(|(index, _)| *index);
+    rules.into_iter().map(|(_, rule)| rule).collect()
+}
+
 fn main() -> Result<(), Error> {
-    let opt = Opt::parse();
-    let source = &opt.source;
-    if !source.is_dir() {
-        eprintln!("{} is not a directory", source.to_string_lossy());
-        process::exit(1);
-    }
+    let matches = Opt::command().get_matches();
+    let opt = Opt::from_arg_matches(&matches)?;
     let destination = &opt.destination;
 
-    let console_info = match opt.error_list_path {
-        Some(err_file) => ConsoleProgressInfo::with_error_list_path(&err_file)?,
-        None => ConsoleProgressInfo::new(),
-    };
+    let mut progress_info = TeeProgressInfo::new();
+    match opt.output {
+        OutputFormat::Text => {
+            let mut console_info = ConsoleProgressInfo::new();
+            console_info.set_dry_run(opt.dry_run);
+            console_info.set_itemize(opt.itemize);
+            progress_info.push(Box::new(console_info));
+        }
+        OutputFormat::Json => progress_info.push(Box::new(JsonProgressInfo::new())),
+    }
+    if let Some(log_file) = &opt.log_file {
+        let log_info = LogProgressInfo::new(log_file, opt.out_format.clone())?;
+        progress_info.push(Box::new(log_info));
+    }
+    if let Some(error_list_path) = &opt.error_list_path {
+        let error_list = ErrorListProgressInfo::new(error_list_path, opt.error_list_format)?;
+        progress_info.push(Box::new(error_list));
+    }
     let options = SyncOptions {
         preserve_permissions: !opt.no_preserve_permissions,
+        delete: opt.delete,
+        dry_run: opt.dry_run,
+        checksum: opt.checksum,
+        preserve_times: !opt.no_preserve_times,
+        inplace: opt.inplace,
+        filters: filter_rules(&matches, &opt),
+        ignore_files: opt.ignore_files,
+        jobs: opt.jobs,
+        delta: opt.delta,
+        hard_links: opt.hard_links,
+        reflink: opt.reflink,
+        sparse: opt.sparse,
+        xattrs: opt.xattrs,
+        acls: opt.acls,
+        owner: opt.owner,
+        group: opt.group,
+        numeric_ids: opt.numeric_ids,
+        usermap: opt.usermap.clone(),
+        groupmap: opt.groupmap.clone(),
     };
-    let syncer = Syncer::new(source, destination, options, Box::new(console_info));
+    let syncer = Syncer::with_sources(&opt.sources, destination, options, Box::new(progress_info));
     let stats = syncer.sync();
     match stats {
         Err(err) => {
@@ -0,0 +1,242 @@
+//! ownership
+//!
+//! Preserve the owner and group of the source entries, optionally
+//! translating them with user and group maps.
+//!
+//! Source and destination live on the same machine, so matching ids by name
+//! or by number gives the same result. With `SyncOptions::numeric_ids`, names
+//! are never looked up, and the maps may only contain numeric ids.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Error};
+
+use crate::entry::Entry;
+use crate::sync::SyncOptions;
+
+/// Translate a user or a group, given by name or by numeric id.
+/// Parsed from `FROM:TO`, where `FROM` can also be `*` to match everything
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdMapping {
+    pub from: String,
+    pub to: String,
+}
+
+impl FromStr for IdMapping {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((from, to)) if !from.is_empty() && !to.is_empty() => Ok(IdMapping {
+                from: from.to_string(),
+                to: to.to_string(),
+            }),
+            _ => Err(anyhow!("Invalid id mapping: '{}' (expected FROM:TO)", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum IdKind {
+    User,
+    Group,
+}
+
+impl fmt::Display for IdKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IdKind::User => write!(f, "user"),
+            IdKind::Group => write!(f, "group"),
+        }
+    }
+}
+
+/// Mappings resolved to numeric ids. `None` matches every id
+#[derive(Debug, Clone, Default)]
+struct IdMap {
+    rules: Vec<(Option<u32>, u32)>,
+}
+
+impl IdMap {
+    fn new(mappings: &[IdMapping], kind: IdKind, numeric_ids: bool) -> Result<IdMap, Error> {
+        let mut rules = vec![];
+        for mapping in mappings {
+            let from = match mapping.from.as_str() {
+                "*" => None,
+                from => Some(resolve(from, kind, numeric_ids)?),
+            };
+            let to = resolve(&mapping.to, kind, numeric_ids)?;
+            rules.push((from, to));
+        }
+        Ok(IdMap { rules })
+    }
+
+    /// The first matching mapping wins. Ids not matched by any mapping are kept
+    fn map(&self, id: u32) -> u32 {
+        self.rules
+            .iter()
+            .find(|(from, _)| from.is_none_or(|from| from == id))
+            .map_or(id, |(_, to)| *to)
+    }
+}
+
+fn resolve(name: &str, kind: IdKind, numeric_ids: bool) -> Result<u32, Error> {
+    if let Ok(id) = name.parse::<u32>() {
+        return Ok(id);
+    }
+    if numeric_ids {
+        bail!("Cannot use {} name '{}' with numeric ids", kind, name);
+    }
+    lookup(name, kind).ok_or_else(|| anyhow!("Unknown {}: '{}'", kind, name))
+}
+
+#[cfg(unix)]
+fn lookup(name: &str, kind: IdKind) -> Option<u32> {
+    use std::ffi::CString;
+    use std::{mem, ptr};
+
+    let name = CString::new(name).ok()?;
+    let mut buffer = vec![0 as libc::c_char; 16 * 1024];
+    match kind {
+        IdKind::User => {
+            let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+            let mut result = ptr::null_mut();
+            let ret = unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut passwd,
+                    buffer.as_mut_ptr(),
+                    buffer.len(),
+                    &mut result,
+                )
+            };
+            (ret == 0 && !result.is_null()).then_some(passwd.pw_uid)
+        }
+        IdKind::Group => {
+            let mut group: libc::group = unsafe { mem::zeroed() };
+            let mut result = ptr::null_mut();
+            let ret = unsafe {
+                libc::getgrnam_r(
+                    name.as_ptr(),
+                    &mut group,
+                    buffer.as_mut_ptr(),
+                    buffer.len(),
+                    &mut result,
+                )
+            };
+            (ret == 0 && !result.is_null()).then_some(group.gr_gid)
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn lookup(_name: &str, _kind: IdKind) -> Option<u32> {
+    None
+}
+
+/// Owner and group to give to the destination entries
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Ownership {
+    owner: Option<IdMap>,
+    group: Option<IdMap>,
+}
+
+impl Ownership {
+    pub fn new(opts: &SyncOptions) -> Result<Ownership, Error> {
+        let owner = opts
+            .owner
+            .then(|| IdMap::new(&opts.usermap, IdKind::User, opts.numeric_ids))
+            .transpose()?;
+        let group = opts
+            .group
+            .then(|| IdMap::new(&opts.groupmap, IdKind::Group, opts.numeric_ids))
+            .transpose()?;
+        Ok(Ownership { owner, group })
+    }
+
+    /// Give `dest` the owner and group of `src`, after translating them.
+    /// Symlinks are not followed. No-op on Windows
+    #[cfg(unix)]
+    pub fn apply(&self, src: &Entry, dest: &Entry) -> Result<(), Error> {
+        use anyhow::{bail, Context};
+
+        if self.owner.is_none() && self.group.is_none() {
+            return Ok(());
+        }
+        if !src.filesystem().is_local() || !dest.filesystem().is_local() {
+            bail!("Ownership can only be preserved on the local filesystem");
+        }
+        let src_meta = src.metadata().expect("src_meta should not be None");
+        let uid = self.owner.as_ref().map(|owner| owner.map(src_meta.uid));
+        let gid = self.group.as_ref().map(|group| group.map(src_meta.gid));
+        // The destination may have been replaced since `dest` was created
+        let dest_meta = dest
+            .filesystem()
+            .symlink_metadata(dest.path())
+            .with_context(|| format!("Could not read metadata of '{}'", dest.description()))?;
+        if uid.is_none_or(|uid| uid == dest_meta.uid) && gid.is_none_or(|gid| gid == dest_meta.gid)
+        {
+            return Ok(());
+        }
+        std::os::unix::fs::lchown(dest.path(), uid, gid)
+            .with_context(|| format!("Could not change ownership of '{}'", dest.description()))
+    }
+
+    #[cfg(not(unix))]
+    pub fn apply(&self, _src: &Entry, _dest: &Entry) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mappings(specs: &[&str]) -> Vec<IdMapping> {
+        specs.iter().map(|spec| spec.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parse_mapping() {
+        let mapping: IdMapping = "alice:1000".parse().unwrap();
+        assert_eq!(mapping.from, "alice");
+        assert_eq!(mapping.to, "1000");
+        assert!("alice".parse::<IdMapping>().is_err());
+        assert!(":1000".parse::<IdMapping>().is_err());
+    }
+
+    #[test]
+    fn first_matching_mapping_wins() {
+        let map = IdMap::new(&mappings(&["1000:2000", "*:3000"]), IdKind::User, true).unwrap();
+        assert_eq!(map.map(1000), 2000);
+        assert_eq!(map.map(1001), 3000);
+    }
+
+    #[test]
+    fn unmatched_ids_are_kept() {
+        let map = IdMap::new(&mappings(&["1000:2000"]), IdKind::Group, true).unwrap();
+        assert_eq!(map.map(42), 42);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn resolve_names() {
+        let map = IdMap::new(&mappings(&["root:1000"]), IdKind::User, false).unwrap();
+        assert_eq!(map.map(0), 1000);
+        let map = IdMap::new(&mappings(&["*:root"]), IdKind::Group, false).unwrap();
+        assert_eq!(map.map(1000), 0);
+    }
+
+    #[test]
+    fn names_are_not_allowed_with_numeric_ids() {
+        let result = IdMap::new(&mappings(&["root:1000"]), IdKind::User, true);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn unknown_name() {
+        let result = IdMap::new(&mappings(&["*:no-such-user"]), IdKind::User, false);
+        assert!(result.is_err());
+    }
+}
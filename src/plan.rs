@@ -0,0 +1,291 @@
+//! plan
+//!
+//! Compute what a sync would do before doing it. See `Syncer::plan()` and
+//! `Syncer::execute()`
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::error::SyncError;
+use crate::progress::{Changes, EntryKind, ProgressInfo, SyncOutcome};
+
+/// What will be done to an entry of the destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Copy a file missing from the destination
+    Copy {
+        size: u64,
+    },
+    /// Replace the contents of an existing file
+    Update {
+        size: u64,
+    },
+    /// Only update the times or permissions of an entry whose contents are
+    /// up to date
+    UpdateAttributes {
+        kind: EntryKind,
+    },
+    CreateSymlink,
+    /// Point an existing symlink to a new target
+    UpdateSymlink,
+    CreateDir,
+    /// Link to another file of the destination, when preserving hard links
+    HardLink,
+    /// Remove an entry that no longer exists in the source
+    Delete,
+}
+
+impl Action {
+    /// Return the action for an entry synced with `outcome` in dry-run mode,
+    /// or None if it was up to date
+    fn from_outcome(outcome: &SyncOutcome) -> Option<Self> {
+        let action = match outcome {
+            SyncOutcome::UpToDate => return None,
+            SyncOutcome::AttributesUpdated { kind, .. } => Action::UpdateAttributes { kind: *kind },
+            SyncOutcome::FileCopied { size, changes } if changes.created => {
+                Action::Copy { size: *size }
+            }
+            SyncOutcome::FileCopied { size, .. } | SyncOutcome::FilePatched { size, .. } => {
+                Action::Update { size: *size }
+            }
+            SyncOutcome::SymlinkCreated => Action::CreateSymlink,
+            SyncOutcome::SymlinkUpdated => Action::UpdateSymlink,
+            SyncOutcome::DirCreated => Action::CreateDir,
+            SyncOutcome::HardLinked => Action::HardLink,
+            SyncOutcome::Deleted => Action::Delete,
+        };
+        Some(action)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::Copy { .. } => "copy",
+            Action::Update { .. } => "update",
+            Action::UpdateAttributes { .. } => "update_attributes",
+            Action::CreateSymlink => "create_symlink",
+            Action::UpdateSymlink => "update_symlink",
+            Action::CreateDir => "create_dir",
+            Action::HardLink => "hard_link",
+            Action::Delete => "delete",
+        }
+    }
+
+    /// Number of bytes to write
+    pub fn size(&self) -> u64 {
+        match self {
+            Action::Copy { size } | Action::Update { size } => *size,
+            _ => 0,
+        }
+    }
+}
+
+/// An action, along with the entry it applies to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedAction {
+    /// Name of the entry, relative to the destination
+    pub entry: String,
+    pub action: Action,
+    /// Why the action is needed: what differs between the source and the
+    /// destination. Empty for deletions
+    pub reasons: Changes,
+}
+
+impl PlannedAction {
+    fn from_outcome(entry: &str, outcome: &SyncOutcome) -> Option<Self> {
+        let action = Action::from_outcome(outcome)?;
+        let reasons = match outcome {
+            SyncOutcome::AttributesUpdated { changes, .. }
+            | SyncOutcome::FileCopied { changes, .. }
+            | SyncOutcome::FilePatched { changes, .. } => *changes,
+            SyncOutcome::SymlinkCreated | SyncOutcome::DirCreated | SyncOutcome::HardLinked => {
+                Changes {
+                    created: true,
+                    ..Default::default()
+                }
+            }
+            SyncOutcome::SymlinkUpdated => Changes {
+                target: true,
+                ..Default::default()
+            },
+            SyncOutcome::UpToDate | SyncOutcome::Deleted => Changes::default(),
+        };
+        Some(Self {
+            entry: entry.to_string(),
+            action,
+            reasons,
+        })
+    }
+}
+
+/// Everything a sync would do, as returned by `Syncer::plan()`. Actions can be
+/// removed before passing the plan to `Syncer::execute()`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncPlan {
+    /// Actions sorted by entry name. Entries that are up to date are not listed
+    pub actions: Vec<PlannedAction>,
+    /// Entries that could not be checked while planning
+    pub errors: Vec<SyncError>,
+}
+
+impl SyncPlan {
+    pub fn is_empty(&self) -> bool {
+        self.actions.is_empty()
+    }
+
+    /// Number of bytes the plan would write
+    pub fn total_size(&self) -> u64 {
+        self.actions.iter().map(|action| action.action.size()).sum()
+    }
+}
+
+/// Builds a plan from the outcomes of a dry run
+#[derive(Clone, Default)]
+pub(crate) struct PlanCollector {
+    plan: Arc<Mutex<SyncPlan>>,
+}
+
+impl PlanCollector {
+    /// Return the plan once the dry run is over
+    pub fn into_plan(self) -> SyncPlan {
+        let mut plan = match self.plan.lock() {
+            Ok(plan) => plan.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+        plan.actions.sort_by(|a, b| a.entry.cmp(&b.entry));
+        plan
+    }
+}
+
+impl ProgressInfo for PlanCollector {
+    fn synced(&mut self, name: &str, outcome: &SyncOutcome) {
+        if let Some(action) = PlannedAction::from_outcome(name, outcome) {
+            if let Ok(mut plan) = self.plan.lock() {
+                plan.actions.push(action);
+            }
+        }
+    }
+
+    fn error(&mut self, error: &SyncError) {
+        if let Ok(mut plan) = self.plan.lock() {
+            plan.errors.push(error.clone());
+        }
+    }
+}
+
+/// The entries of a plan, as relative paths in the destination. The sync only
+/// touches these entries, and the directories containing them
+#[derive(Debug, Default)]
+pub(crate) struct PlanSelection {
+    synced: HashSet<PathBuf>,
+    deleted: HashSet<PathBuf>,
+    /// Directories containing at least one of the entries
+    parents: HashSet<PathBuf>,
+}
+
+impl PlanSelection {
+    pub fn new(plan: &SyncPlan) -> Self {
+        let mut selection = Self::default();
+        for action in &plan.actions {
+            let path = PathBuf::from(&action.entry);
+            selection.parents.extend(
+                path.ancestors()
+                    .skip(1)
+                    .filter(|parent| !parent.as_os_str().is_empty())
+                    .map(Path::to_path_buf),
+            );
+            if action.action == Action::Delete {
+                selection.deleted.insert(path);
+            } else {
+                selection.synced.insert(path);
+            }
+        }
+        selection
+    }
+
+    /// Wether the source entry at `rel_path` must be synced
+    pub fn is_synced(&self, rel_path: &Path) -> bool {
+        self.synced.contains(rel_path) || self.parents.contains(rel_path)
+    }
+
+    /// Wether the destination entry at `rel_path` can be deleted
+    pub fn is_deleted(&self, rel_path: &Path) -> bool {
+        self.deleted.contains(rel_path)
+    }
+
+    /// Wether the destination directory at `rel_path` may contain
+    /// entries to delete
+    pub fn contains_deleted(&self, rel_path: &Path) -> bool {
+        self.parents.contains(rel_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn planned(entry: &str, outcome: SyncOutcome) -> PlannedAction {
+        PlannedAction::from_outcome(entry, &outcome).unwrap()
+    }
+
+    #[test]
+    fn actions_from_outcomes() {
+        let created = Changes {
+            created: true,
+            ..Default::default()
+        };
+        let resized = Changes {
+            size: true,
+            mtime: true,
+            ..Default::default()
+        };
+        let copied = planned(
+            "a",
+            SyncOutcome::FileCopied {
+                size: 3,
+                changes: created,
+            },
+        );
+        assert_eq!(copied.action, Action::Copy { size: 3 });
+        assert!(copied.reasons.created);
+        let updated = planned(
+            "b",
+            SyncOutcome::FileCopied {
+                size: 5,
+                changes: resized,
+            },
+        );
+        assert_eq!(updated.action, Action::Update { size: 5 });
+        assert_eq!(updated.reasons, resized);
+        assert_eq!(
+            planned("c", SyncOutcome::DirCreated).action,
+            Action::CreateDir
+        );
+        assert!(planned("d", SyncOutcome::SymlinkUpdated).reasons.target);
+        assert_eq!(
+            planned("e", SyncOutcome::Deleted).reasons,
+            Changes::default()
+        );
+        assert!(PlannedAction::from_outcome("f", &SyncOutcome::UpToDate).is_none());
+    }
+
+    #[test]
+    fn select_entries_and_their_parents() {
+        let plan = SyncPlan {
+            actions: vec![
+                planned("a/b/c.txt", SyncOutcome::DirCreated),
+                planned("d/e.txt", SyncOutcome::Deleted),
+            ],
+            errors: vec![],
+        };
+        let selection = PlanSelection::new(&plan);
+        assert!(selection.is_synced(Path::new("a/b/c.txt")));
+        assert!(selection.is_synced(Path::new("a/b")));
+        assert!(selection.is_synced(Path::new("a")));
+        assert!(!selection.is_synced(Path::new("a/b/other.txt")));
+        assert!(!selection.is_synced(Path::new("d/e.txt")));
+        assert!(selection.is_deleted(Path::new("d/e.txt")));
+        assert!(selection.contains_deleted(Path::new("d")));
+        assert!(!selection.contains_deleted(Path::new("a/b/c.txt")));
+    }
+}
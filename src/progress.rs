@@ -1,9 +1,16 @@
-use crate::fsops::SyncOutcome;
+use std::sync::Arc;
+
+pub use crate::error::SyncError;
+use crate::filesystem::Filesystem;
+pub use crate::fsops::{Changes, EntryKind, SyncOutcome};
 use crate::sync::Stats;
 
 #[doc(hidden)]
 pub enum ProgressMessage {
-    DoneSyncing(SyncOutcome),
+    DoneSyncing {
+        entry: String,
+        outcome: SyncOutcome,
+    },
     StartSync(String),
     Todo {
         num_files: u64,
@@ -14,10 +21,8 @@ pub enum ProgressMessage {
         size: usize,
         done: usize,
     },
-    SyncError {
-        entry: String,
-        details: String,
-    },
+    SyncError(SyncError),
+    Cancelled,
 }
 
 pub struct Progress {
@@ -41,6 +46,11 @@ pub struct Progress {
 
 /// Trait for implementing rusync progress details
 pub trait ProgressInfo {
+    /// Called before `start()` with the filesystem the destination is written
+    /// to, for progress infos that look at the synced entries
+    #[allow(unused_variables)]
+    fn destination_filesystem(&mut self, filesystem: &Arc<dyn Filesystem>) {}
+
     /// A new transfer has begun from the `source` directory to the `destination`
     /// directory
     #[allow(unused_variables)]
@@ -54,6 +64,11 @@ pub trait ProgressInfo {
     #[allow(unused_variables)]
     fn done_syncing(&mut self) {}
 
+    /// The entry named `name` has been synced with the given `outcome`.
+    /// In dry-run mode, the outcome is what would have been done
+    #[allow(unused_variables)]
+    fn synced(&mut self, name: &str, outcome: &SyncOutcome) {}
+
     /// Callback for the detailed progress
     #[allow(unused_variables)]
     fn progress(&mut self, progress: &Progress) {}
@@ -63,7 +78,130 @@ pub trait ProgressInfo {
     #[allow(unused_variables)]
     fn end(&mut self, stats: &Stats) {}
 
-    /// The entry could not be synced
+    /// An entry could not be synced
     #[allow(unused_variables)]
-    fn error(&mut self, entry: &str, details: &str) {}
+    fn error(&mut self, error: &SyncError) {}
+}
+
+/// Forwards every callback to several `ProgressInfo` implementations, in the
+/// order they were added. Use it to display progress on the console while
+/// also writing a log file, for instance
+#[derive(Default)]
+pub struct TeeProgressInfo {
+    sinks: Vec<Box<dyn ProgressInfo + Send>>,
+}
+
+impl TeeProgressInfo {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, sink: Box<dyn ProgressInfo + Send>) {
+        self.sinks.push(sink);
+    }
+}
+
+impl ProgressInfo for TeeProgressInfo {
+    fn destination_filesystem(&mut self, filesystem: &Arc<dyn Filesystem>) {
+        for sink in &mut self.sinks {
+            sink.destination_filesystem(filesystem);
+        }
+    }
+
+    fn start(&mut self, source: &str, destination: &str) {
+        for sink in &mut self.sinks {
+            sink.start(source, destination);
+        }
+    }
+
+    fn new_file(&mut self, name: &str) {
+        for sink in &mut self.sinks {
+            sink.new_file(name);
+        }
+    }
+
+    fn done_syncing(&mut self) {
+        for sink in &mut self.sinks {
+            sink.done_syncing();
+        }
+    }
+
+    fn synced(&mut self, name: &str, outcome: &SyncOutcome) {
+        for sink in &mut self.sinks {
+            sink.synced(name, outcome);
+        }
+    }
+
+    fn progress(&mut self, progress: &Progress) {
+        for sink in &mut self.sinks {
+            sink.progress(progress);
+        }
+    }
+
+    fn end(&mut self, stats: &Stats) {
+        for sink in &mut self.sinks {
+            sink.end(stats);
+        }
+    }
+
+    fn error(&mut self, error: &SyncError) {
+        for sink in &mut self.sinks {
+            sink.error(error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Records the name of each callback
+    struct Recorder {
+        id: usize,
+        calls: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl ProgressInfo for Recorder {
+        fn start(&mut self, _source: &str, _destination: &str) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("{} start", self.id));
+        }
+
+        fn synced(&mut self, name: &str, _outcome: &SyncOutcome) {
+            let call = format!("{} synced {}", self.id, name);
+            self.calls.lock().unwrap().push(call);
+        }
+
+        fn end(&mut self, _stats: &Stats) {
+            self.calls.lock().unwrap().push(format!("{} end", self.id));
+        }
+    }
+
+    #[test]
+    fn forward_callbacks_to_every_sink() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let mut tee = TeeProgressInfo::new();
+        for id in 0..2 {
+            let calls = calls.clone();
+            tee.push(Box::new(Recorder { id, calls }));
+        }
+        tee.start("src", "dest");
+        tee.synced("foo", &SyncOutcome::DirCreated);
+        tee.end(&Stats::new());
+        let calls = calls.lock().unwrap();
+        assert_eq!(
+            *calls,
+            [
+                "0 start",
+                "1 start",
+                "0 synced foo",
+                "1 synced foo",
+                "0 end",
+                "1 end"
+            ]
+        );
+    }
 }
@@ -0,0 +1,57 @@
+//! reflink
+//!
+//! Linux fast paths for copying file contents: cloning the extents of the source
+//! on copy-on-write filesystems (btrfs, XFS, ...), and letting the kernel copy the
+//! data with copy_file_range(), without going through a userspace buffer.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+const COPY_RANGE_SIZE: usize = 8 * 1024 * 1024;
+
+/// Make `dest` share the extents of `src`
+pub fn clone_file(src: &File, dest: &File) -> io::Result<()> {
+    let ret = unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Copy the rest of `src` to `dest` with copy_file_range(), starting at the current
+/// offsets of both files. Return false if the kernel or the filesystems do not
+/// support it, in which case the copy can be finished some other way. The copy
+/// stops as soon as `on_progress` returns an error
+pub fn copy_file_range<P>(src: &File, dest: &File, mut on_progress: P) -> io::Result<bool>
+where
+    P: FnMut(usize) -> io::Result<()>,
+{
+    loop {
+        let ret = unsafe {
+            libc::copy_file_range(
+                src.as_raw_fd(),
+                ptr::null_mut(),
+                dest.as_raw_fd(),
+                ptr::null_mut(),
+                COPY_RANGE_SIZE,
+                0,
+            )
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::EINTR) => continue,
+                Some(
+                    libc::ENOSYS | libc::EXDEV | libc::EOPNOTSUPP | libc::EINVAL | libc::EPERM,
+                ) => Ok(false),
+                _ => Err(err),
+            };
+        }
+        if ret == 0 {
+            return Ok(true);
+        }
+        on_progress(ret as usize)?;
+    }
+}
@@ -0,0 +1,193 @@
+//! sparse
+//!
+//! Copy files without writing their holes: ranges of the source that are
+//! not allocated (found with SEEK_DATA and SEEK_HOLE on Linux), or that only
+//! contain zeros, are skipped in the destination.
+
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+
+// Blocks of zeros smaller than this are written anyway
+const BLOCK_SIZE: usize = 4096;
+const BUFFER_SIZE: usize = 25 * BLOCK_SIZE;
+
+/// Copy `size` bytes from `src` to `dest`, which must be empty. `on_progress`
+/// is called with the number of bytes processed, holes included, and the copy
+/// stops as soon as it returns an error
+pub fn copy_sparse<P>(
+    src: &mut File,
+    dest: &mut File,
+    size: u64,
+    mut on_progress: P,
+) -> io::Result<()>
+where
+    P: FnMut(usize) -> io::Result<()>,
+{
+    let mut buffer = vec![0; BUFFER_SIZE];
+    let mut offset = 0;
+    while offset < size {
+        let (start, end) = match next_data(src, offset, size)? {
+            Some(range) => range,
+            None => break,
+        };
+        if start > offset {
+            on_progress((start - offset) as usize)?;
+        }
+        copy_range(src, dest, start, end, &mut buffer, &mut on_progress)?;
+        offset = end;
+    }
+    if offset < size {
+        on_progress((size - offset) as usize)?;
+    }
+    // Holes at the end of the file only exist once its length is set
+    dest.set_len(size)
+}
+
+/// Return the next range of `src` that may contain data, starting at `offset`
+#[cfg(target_os = "linux")]
+fn next_data(src: &File, offset: u64, size: u64) -> io::Result<Option<(u64, u64)>> {
+    let fd = src.as_raw_fd();
+    let start = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+    if start < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            // Only a hole after offset
+            Some(libc::ENXIO) => Ok(None),
+            // Holes cannot be found on this filesystem, look for zeros instead
+            Some(libc::EINVAL | libc::EOPNOTSUPP) => Ok(Some((offset, size))),
+            _ => Err(err),
+        };
+    }
+    let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+    if end < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let start = cmp::min(start as u64, size);
+    let end = cmp::min(end as u64, size);
+    Ok(Some((start, end)))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn next_data(_src: &File, offset: u64, size: u64) -> io::Result<Option<(u64, u64)>> {
+    Ok(Some((offset, size)))
+}
+
+fn copy_range<P>(
+    src: &mut File,
+    dest: &mut File,
+    start: u64,
+    end: u64,
+    buffer: &mut [u8],
+    on_progress: &mut P,
+) -> io::Result<()>
+where
+    P: FnMut(usize) -> io::Result<()>,
+{
+    src.seek(SeekFrom::Start(start))?;
+    dest.seek(SeekFrom::Start(start))?;
+    let mut remaining = end - start;
+    while remaining > 0 {
+        let len = cmp::min(remaining, buffer.len() as u64) as usize;
+        src.read_exact(&mut buffer[..len])?;
+        write_sparse(dest, &buffer[..len])?;
+        on_progress(len)?;
+        remaining -= len as u64;
+    }
+    Ok(())
+}
+
+/// Write `data` at the current position of `dest`, seeking over blocks of zeros
+fn write_sparse(dest: &mut File, data: &[u8]) -> io::Result<()> {
+    for block in data.chunks(BLOCK_SIZE) {
+        if block.iter().all(|&b| b == 0) {
+            dest.seek(SeekFrom::Current(block.len() as i64))?;
+        } else {
+            dest.write_all(block)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn allocated(path: &Path) -> u64 {
+        std::fs::metadata(path).unwrap().blocks() * 512
+    }
+
+    fn copy(src: &Path, dest: &Path) -> io::Result<u64> {
+        let mut src_file = File::open(src)?;
+        let mut dest_file = File::create(dest)?;
+        let size = src_file.metadata()?.len();
+        let mut done = 0;
+        copy_sparse(&mut src_file, &mut dest_file, size, |n| {
+            done += n as u64;
+            Ok(())
+        })?;
+        Ok(done)
+    }
+
+    #[test]
+    fn holes_are_preserved() -> io::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let src = tmp_dir.path().join("src.img");
+        let dest = tmp_dir.path().join("dest.img");
+        let mut src_file = File::create(&src)?;
+        src_file.set_len(10 * MIB)?;
+        src_file.write_all(b"header")?;
+        src_file.seek(SeekFrom::Start(5 * MIB))?;
+        src_file.write_all(b"middle")?;
+        drop(src_file);
+
+        let done = copy(&src, &dest)?;
+
+        assert_eq!(done, 10 * MIB);
+        assert!(std::fs::read(&src)? == std::fs::read(&dest)?);
+        assert!(allocated(&dest) < MIB);
+        Ok(())
+    }
+
+    #[test]
+    fn zeros_become_holes() -> io::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let src = tmp_dir.path().join("src.img");
+        let dest = tmp_dir.path().join("dest.img");
+        let mut contents = vec![0; 4 * MIB as usize];
+        contents[..5].copy_from_slice(b"start");
+        std::fs::write(&src, &contents)?;
+
+        copy(&src, &dest)?;
+
+        assert!(std::fs::read(&dest)? == contents);
+        assert!(allocated(&src) >= 4 * MIB);
+        assert!(allocated(&dest) < MIB);
+        Ok(())
+    }
+
+    #[test]
+    fn trailing_hole() -> io::Result<()> {
+        let tmp_dir = TempDir::new()?;
+        let src = tmp_dir.path().join("src.img");
+        let dest = tmp_dir.path().join("dest.img");
+        let mut src_file = File::create(&src)?;
+        src_file.write_all(b"data")?;
+        src_file.set_len(MIB)?;
+        drop(src_file);
+
+        copy(&src, &dest)?;
+
+        assert_eq!(std::fs::metadata(&dest)?.len(), MIB);
+        assert!(std::fs::read(&src)? == std::fs::read(&dest)?);
+        Ok(())
+    }
+}
@@ -1,17 +1,31 @@
+use std::collections::{BTreeMap, HashSet};
 use std::path::Path;
 use std::path::PathBuf;
-use std::sync::mpsc::channel;
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
 use std::thread;
 
-use anyhow::{anyhow, Error};
+use anyhow::{anyhow, Context, Error};
 
+use crate::control::{SyncControl, SyncHandle};
 use crate::entry::Entry;
+use crate::error::{ErrorPhase, SyncError};
+use crate::filesystem::{Filesystem, LocalFilesystem};
+use crate::filter::{FilterRule, Filters};
 use crate::fsops;
 use crate::fsops::SyncOutcome::*;
-use crate::progress::{ProgressInfo, ProgressMessage};
+use crate::ownership::{IdMapping, Ownership};
+use crate::plan::{PlanCollector, PlanSelection, SyncPlan};
+use crate::progress::{ProgressInfo, ProgressMessage, TeeProgressInfo};
+use crate::workers::DeleteWorker;
+use crate::workers::HardLink;
 use crate::workers::ProgressWorker;
+use crate::workers::SourceEntry;
 use crate::workers::SyncWorker;
 use crate::workers::WalkWorker;
+#[cfg(unix)]
+use crate::xattrs;
 
 #[derive(Debug)]
 pub struct Stats {
@@ -21,6 +35,10 @@ pub struct Stats {
     pub total_size: usize,
     /// Sum of the sizes of all the files that were synced
     pub total_transfered: u64,
+    /// Number of bytes written from the source
+    pub literal_bytes: u64,
+    /// Number of bytes reused from existing destination files (see `SyncOptions::delta`)
+    pub matched_bytes: u64,
 
     /// Number of files transfered (should match `num_files`
     /// if no error)
@@ -37,6 +55,18 @@ pub struct Stats {
     /// Number of symlinks updated in the destination folder
     pub symlink_updated: u64,
 
+    /// Number of directories created in the destination folder
+    pub dirs_created: u64,
+
+    /// Number of hard links created in the destination folder
+    pub hard_linked: u64,
+
+    /// Number of entries removed from the destination folder
+    pub deleted: u64,
+
+    /// Wether the sync was cancelled before it was done, see `SyncHandle::cancel()`
+    pub cancelled: bool,
+
     /// Duration of the transfer
     pub duration: std::time::Duration,
 
@@ -50,6 +80,8 @@ impl Stats {
             num_files: 0,
             total_size: 0,
             total_transfered: 0,
+            literal_bytes: 0,
+            matched_bytes: 0,
 
             num_synced: 0,
             up_to_date: 0,
@@ -58,6 +90,10 @@ impl Stats {
 
             symlink_created: 0,
             symlink_updated: 0,
+            dirs_created: 0,
+            hard_linked: 0,
+            deleted: 0,
+            cancelled: false,
             start: std::time::Instant::now(),
             duration: std::time::Duration::new(0, 0),
         }
@@ -82,41 +118,256 @@ impl Stats {
 
     #[doc(hidden)]
     pub fn add_outcome(&mut self, outcome: &fsops::SyncOutcome) {
-        self.num_synced += 1;
         match outcome {
-            FileCopied { size } => {
+            FileCopied { size, .. } => {
                 self.copied += 1;
                 self.total_transfered += size;
+                self.literal_bytes += size;
             }
-            UpToDate => self.up_to_date += 1,
+            FilePatched {
+                size,
+                literal,
+                matched,
+                ..
+            } => {
+                self.copied += 1;
+                self.total_transfered += size;
+                self.literal_bytes += literal;
+                self.matched_bytes += matched;
+            }
+            UpToDate | AttributesUpdated { .. } => self.up_to_date += 1,
             SymlinkUpdated => self.symlink_updated += 1,
             SymlinkCreated => self.symlink_created += 1,
+            DirCreated => self.dirs_created += 1,
+            HardLinked => self.hard_linked += 1,
+            Deleted => {
+                // Deleted entries are not in the source, so they
+                // do not count as synced
+                self.deleted += 1;
+                return;
+            }
+        }
+        self.num_synced += 1;
+    }
+}
+
+/// How the contents of files are copied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReflinkMode {
+    /// Clone files on copy-on-write filesystems, and let the kernel copy the data
+    /// when possible. Fall back to copying the data otherwise
+    Auto,
+    /// Clone files, and fail if the filesystem does not support it (Linux only)
+    Always,
+    /// Always copy the data through a buffer
+    Never,
+}
+
+impl FromStr for ReflinkMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ReflinkMode::Auto),
+            "always" => Ok(ReflinkMode::Always),
+            "never" => Ok(ReflinkMode::Never),
+            _ => Err(anyhow!(
+                "Invalid reflink mode: '{}' (expected auto, always or never)",
+                s
+            )),
         }
     }
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone)]
 pub struct SyncOptions {
     /// Wether to preserve permissions of the source file after the destination is written.
     pub preserve_permissions: bool,
+    /// Wether to remove entries from the destination that do not exist in the source.
+    pub delete: bool,
+    /// Wether to only report what would be done, without touching the destination.
+    pub dry_run: bool,
+    /// Wether to compare the contents of files having the same size, instead of
+    /// relying on their modification times.
+    pub checksum: bool,
+    /// Wether to preserve access and modification times of the source entries.
+    pub preserve_times: bool,
+    /// Wether to write directly to the destination files instead of using a temporary
+    /// file. This uses less disk space, but partially written files may be left behind.
+    pub inplace: bool,
+    /// Include and exclude rules, checked in order. See the [filter](../filter/index.html)
+    /// module for details.
+    pub filters: Vec<FilterRule>,
+    /// Wether to skip entries matched by the `.gitignore`, `.ignore` and `.rusyncignore`
+    /// files found in the source directories.
+    pub ignore_files: bool,
+    /// Number of entries to sync at the same time. Using more than one job
+    /// may speed up syncing many small files.
+    pub jobs: usize,
+    /// Wether to only write the parts of existing destination files that changed,
+    /// using an rsync-like delta algorithm. Ignored when `inplace` is set.
+    pub delta: bool,
+    /// Wether to recreate hard links between source files in the destination,
+    /// instead of copying each of them (no-op on Windows).
+    pub hard_links: bool,
+    /// Wether to clone the source files instead of copying their data, on
+    /// filesystems supporting it.
+    pub reflink: ReflinkMode,
+    /// Wether to recreate the holes of sparse files in the destination, instead of
+    /// writing zeros. Blocks of zeros in the source also become holes.
+    pub sparse: bool,
+    /// Wether to copy extended attributes, except ACLs (no-op on Windows).
+    pub xattrs: bool,
+    /// Wether to copy POSIX ACLs (no-op on Windows).
+    pub acls: bool,
+    /// Wether to preserve the owner of the source entries. This usually
+    /// requires running as root (no-op on Windows).
+    pub owner: bool,
+    /// Wether to preserve the group of the source entries (no-op on Windows).
+    pub group: bool,
+    /// Wether to never look up user and group names. See the
+    /// [ownership](../ownership/index.html) module for details.
+    pub numeric_ids: bool,
+    /// How to translate the owners of the source entries, when `owner` is set.
+    pub usermap: Vec<IdMapping>,
+    /// How to translate the groups of the source entries, when `group` is set.
+    pub groupmap: Vec<IdMapping>,
 }
 
 impl Default for SyncOptions {
     fn default() -> Self {
         Self {
             preserve_permissions: true,
+            delete: false,
+            dry_run: false,
+            checksum: false,
+            preserve_times: true,
+            inplace: false,
+            filters: vec![],
+            ignore_files: false,
+            jobs: 1,
+            delta: false,
+            hard_links: false,
+            reflink: ReflinkMode::Auto,
+            sparse: false,
+            xattrs: false,
+            acls: false,
+            owner: false,
+            group: false,
+            numeric_ids: false,
+            usermap: vec![],
+            groupmap: vec![],
+        }
+    }
+}
+
+/// A source given to the syncer, and where it is synced
+#[derive(Debug, Clone)]
+pub(crate) struct SourceRoot {
+    /// Path of the source file or directory
+    pub path: PathBuf,
+    /// Where the source is synced, relative to the destination. Empty when
+    /// the contents of a directory are synced directly in the destination
+    pub rel_path: PathBuf,
+    /// Wether the source is a directory, or a link to a directory
+    pub is_dir: bool,
+}
+
+/// Where the sources are read, and where the destination is written
+#[derive(Debug, Clone)]
+pub(crate) struct Filesystems {
+    pub source: Arc<dyn Filesystem>,
+    pub destination: Arc<dyn Filesystem>,
+}
+
+impl Default for Filesystems {
+    fn default() -> Self {
+        Self {
+            source: Arc::new(LocalFilesystem),
+            destination: Arc::new(LocalFilesystem),
         }
     }
 }
 
+/// Sources given to the syncer, resolved in `Syncer::roots()` once
+/// the filesystems are known
+#[derive(Debug, Clone)]
+enum Sources {
+    /// A directory whose contents are synced, or a single file
+    Contents(PathBuf),
+    /// Files and directories synced with rsync conventions
+    Paths(Vec<PathBuf>),
+}
+
+fn is_dir(filesystem: &dyn Filesystem, path: &Path) -> bool {
+    filesystem.metadata(path).is_ok_and(|m| m.is_dir())
+}
+
+fn has_trailing_slash(path: &Path) -> bool {
+    path.as_os_str()
+        .as_encoded_bytes()
+        .last()
+        .is_some_and(|&b| std::path::is_separator(b as char))
+}
+
+/// Figure out where each source goes, using rsync conventions. Return the
+/// roots and the directory they are synced in
+fn source_roots(
+    sources: &[PathBuf],
+    destination: &Path,
+    filesystems: &Filesystems,
+) -> (Vec<SourceRoot>, PathBuf) {
+    let source_is_dir = |source: &Path| is_dir(filesystems.source.as_ref(), source);
+    if let [source] = sources {
+        let dest_is_dir = is_dir(filesystems.destination.as_ref(), destination)
+            || has_trailing_slash(destination);
+        // A single file can be synced to a new name
+        if let (false, false, Some(name)) =
+            (source_is_dir(source), dest_is_dir, destination.file_name())
+        {
+            let parent = destination
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new("."));
+            let root = SourceRoot {
+                path: source.to_path_buf(),
+                rel_path: PathBuf::from(name),
+                is_dir: false,
+            };
+            return (vec![root], parent.to_path_buf());
+        }
+    }
+    let roots = sources
+        .iter()
+        .map(|source| {
+            let is_dir = source_is_dir(source);
+            // `dir/` means the contents of `dir`, and so does `.`
+            let rel_path = match source.file_name() {
+                Some(_) if has_trailing_slash(source) && is_dir => PathBuf::new(),
+                Some(name) => PathBuf::from(name),
+                None => PathBuf::new(),
+            };
+            SourceRoot {
+                path: source.to_path_buf(),
+                rel_path,
+                is_dir,
+            }
+        })
+        .collect();
+    (roots, destination.to_path_buf())
+}
+
 pub struct Syncer {
-    source: PathBuf,
+    sources: Sources,
     destination: PathBuf,
+    filesystems: Filesystems,
     options: SyncOptions,
-    progress_info: Box<dyn ProgressInfo + Send>,
+    progress_info: TeeProgressInfo,
 }
 
 impl Syncer {
+    /// Sync the contents of the `source` directory in `destination`. If `source`
+    /// is a file, this is the same as calling `with_sources()` with just `source`
     pub fn new(
         source: &Path,
         destination: &Path,
@@ -124,46 +375,411 @@ impl Syncer {
         progress_info: Box<dyn ProgressInfo + Send>,
     ) -> Syncer {
         Syncer {
-            source: source.to_path_buf(),
+            sources: Sources::Contents(source.to_path_buf()),
             destination: destination.to_path_buf(),
-            progress_info,
+            filesystems: Filesystems::default(),
+            progress_info: TeeProgressInfo::new(),
             options,
         }
+        .add_progress_info(progress_info)
+    }
+
+    /// Sync several files and directories in `destination`, like rsync does:
+    ///
+    /// * `dir` is synced to `destination/dir`
+    /// * `dir/` means the contents of `dir`, which are synced directly in `destination`
+    /// * `file` is synced to `destination/file` - except when it is the only source and
+    ///   `destination` is not a directory, nor ends with a `/`: then `file` is synced
+    ///   to `destination` itself
+    pub fn with_sources<P: AsRef<Path>>(
+        sources: &[P],
+        destination: &Path,
+        options: SyncOptions,
+        progress_info: Box<dyn ProgressInfo + Send>,
+    ) -> Syncer {
+        let sources = sources.iter().map(|s| s.as_ref().to_path_buf()).collect();
+        Syncer {
+            sources: Sources::Paths(sources),
+            destination: destination.to_path_buf(),
+            filesystems: Filesystems::default(),
+            progress_info: TeeProgressInfo::new(),
+            options,
+        }
+        .add_progress_info(progress_info)
+    }
+
+    /// Also report progress to `progress_info`. Every callback is forwarded
+    /// to each progress info, in the order they were added:
+    ///
+    /// ```no_run
+    /// # let source = std::path::Path::new("src");
+    /// # let destination = std::path::Path::new("dest");
+    /// # let log_path = std::path::Path::new("rusync.log");
+    /// let console_info = rusync::ConsoleProgressInfo::new();
+    /// let log_info = rusync::LogProgressInfo::new(log_path, rusync::OutFormat::default())?;
+    /// let options = rusync::SyncOptions::default();
+    /// let syncer = rusync::Syncer::new(source, destination, options, Box::new(console_info))
+    ///     .add_progress_info(Box::new(log_info));
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn add_progress_info(mut self, progress_info: Box<dyn ProgressInfo + Send>) -> Syncer {
+        self.progress_info.push(progress_info);
+        self
+    }
+
+    /// Read the sources and write the destination in `filesystem` instead of the
+    /// local filesystem. Paths given to the syncer are paths of `filesystem`:
+    ///
+    /// ```
+    /// use std::path::Path;
+    /// use std::sync::Arc;
+    /// use rusync::MemoryFilesystem;
+    ///
+    /// let filesystem = MemoryFilesystem::new();
+    /// filesystem.write("/src/foo.txt", "foo")?;
+    /// let console_info = rusync::ConsoleProgressInfo::new();
+    /// let options = rusync::SyncOptions::default();
+    /// let syncer = rusync::Syncer::new(
+    ///     Path::new("/src"),
+    ///     Path::new("/dest"),
+    ///     options,
+    ///     Box::new(console_info),
+    /// )
+    /// .use_filesystem(Arc::new(filesystem.clone()));
+    /// syncer.sync()?;
+    /// assert_eq!(filesystem.read("/dest/foo.txt")?, b"foo");
+    /// # Ok::<(), anyhow::Error>(())
+    /// ```
+    pub fn use_filesystem(self, filesystem: Arc<dyn Filesystem>) -> Syncer {
+        self.use_filesystems(filesystem.clone(), filesystem)
+    }
+
+    /// Read the sources in `source` and write the destination in `destination`.
+    /// Hard links are always created in the destination filesystem
+    pub fn use_filesystems(
+        mut self,
+        source: Arc<dyn Filesystem>,
+        destination: Arc<dyn Filesystem>,
+    ) -> Syncer {
+        self.filesystems = Filesystems {
+            source,
+            destination,
+        };
+        self
+    }
+
+    /// Figure out where each source goes, now that the filesystems are known
+    fn roots(&self) -> (Vec<SourceRoot>, PathBuf) {
+        match &self.sources {
+            Sources::Contents(source) if is_dir(self.filesystems.source.as_ref(), source) => {
+                let root = SourceRoot {
+                    path: source.clone(),
+                    rel_path: PathBuf::new(),
+                    is_dir: true,
+                };
+                (vec![root], self.destination.clone())
+            }
+            Sources::Contents(source) => source_roots(
+                std::slice::from_ref(source),
+                &self.destination,
+                &self.filesystems,
+            ),
+            Sources::Paths(sources) => source_roots(sources, &self.destination, &self.filesystems),
+        }
     }
 
     pub fn sync(self) -> Result<Stats, Error> {
-        let (walker_entry_output, syncer_input) = channel::<Entry>();
-        let (walker_stats_output, progress_input) = channel::<ProgressMessage>();
-        let progress_output = walker_stats_output.clone();
-
-        let walk_worker = WalkWorker::new(&self.source, walker_entry_output, walker_stats_output);
-        let sync_worker = SyncWorker::new(
-            &self.source,
-            &self.destination,
-            syncer_input,
-            progress_output,
-        );
-        let progress_worker = ProgressWorker::new(progress_input, self.progress_info);
-        let options = self.options;
+        self.run(None, Arc::default())
+    }
 
-        let walker_thread = thread::spawn(move || walk_worker.start());
-        let syncer_thread = thread::spawn(move || sync_worker.start(options));
-        let progress_thread = thread::spawn(|| progress_worker.start());
+    /// Like `sync()`, but return immediately. The sync runs in a background
+    /// thread, and can be paused, resumed or cancelled with the returned handle
+    pub fn spawn(self) -> SyncHandle {
+        let control = Arc::new(SyncControl::default());
+        let sync_control = control.clone();
+        let thread = thread::spawn(move || self.run(None, sync_control));
+        SyncHandle::new(control, thread)
+    }
 
-        walker_thread
-            .join()
-            .map_err(|e| anyhow!("Could not join walker thread: {:?}", e))?;
+    /// Compare the sources with the destination, without touching it, and
+    /// return what the sync would do. The progress infos are not called
+    pub fn plan(&self) -> Result<SyncPlan, Error> {
+        let options = SyncOptions {
+            dry_run: true,
+            ..self.options.clone()
+        };
+        let collector = PlanCollector::default();
+        let progress_info = Box::new(collector.clone());
+        let (roots, destination) = self.roots();
+        run(
+            &roots,
+            &destination,
+            &self.filesystems,
+            options,
+            None,
+            Arc::default(),
+            progress_info,
+        )?;
+        Ok(collector.into_plan())
+    }
+
+    /// Only sync the entries listed in `plan`, which usually comes from
+    /// `plan()` and may have been filtered since. Entries are compared again,
+    /// so nothing is done to those that are up to date by now. The directories
+    /// containing the entries are synced too, so that their times are preserved
+    pub fn execute(self, plan: &SyncPlan) -> Result<Stats, Error> {
+        let selection = Arc::new(PlanSelection::new(plan));
+        self.run(Some(selection), Arc::default())
+    }
+
+    fn run(
+        self,
+        selection: Option<Arc<PlanSelection>>,
+        control: Arc<SyncControl>,
+    ) -> Result<Stats, Error> {
+        let (roots, destination) = self.roots();
+        run(
+            &roots,
+            &destination,
+            &self.filesystems,
+            self.options,
+            selection,
+            control,
+            Box::new(self.progress_info),
+        )
+    }
+}
+
+/// Sync `roots` in `destination`. When `selection` is set, only
+/// the entries of a plan are synced. Once `control` is cancelled, the
+/// workers stop, and hard links and deletions are skipped
+fn run(
+    roots: &[SourceRoot],
+    destination: &Path,
+    filesystems: &Filesystems,
+    options: SyncOptions,
+    selection: Option<Arc<PlanSelection>>,
+    control: Arc<SyncControl>,
+    mut progress_info: Box<dyn ProgressInfo + Send>,
+) -> Result<Stats, Error> {
+    let filters = Filters::new(&options.filters, options.ignore_files)?;
+    let ownership = Ownership::new(&options)?;
+    // Missing sources are reported by the walker, and the other ones are
+    // still synced. There is nothing to sync if none of them can be read
+    let mut unreadable = roots.iter().filter_map(|root| {
+        let metadata = filesystems.source.symlink_metadata(&root.path);
+        metadata.err().map(|e| (root, e))
+    });
+    if let Some((root, e)) = unreadable.next() {
+        if unreadable.count() + 1 == roots.len() {
+            return Err(e)
+                .with_context(|| format!("Could not read source '{}'", root.path.display()));
+        }
+    }
+    let (walker_entry_output, syncer_input) = channel::<SourceEntry>();
+    let (walker_stats_output, progress_input) = channel::<ProgressMessage>();
+    let delete_output = walker_stats_output.clone();
+    let finalize_output = walker_stats_output.clone();
+
+    let syncer_input = Arc::new(Mutex::new(syncer_input));
+    let cleaned_dirs = Arc::new(Mutex::new(HashSet::new()));
+    let sync_workers: Vec<_> = (0..options.jobs.max(1))
+        .map(|_| {
+            SyncWorker::new(
+                filesystems.destination.clone(),
+                destination,
+                syncer_input.clone(),
+                cleaned_dirs.clone(),
+                ownership.clone(),
+                control.clone(),
+                walker_stats_output.clone(),
+            )
+        })
+        .collect();
+    let mut walk_worker = WalkWorker::new(
+        filesystems.source.clone(),
+        roots,
+        filters.clone(),
+        options.hard_links,
+        control.clone(),
+        walker_entry_output,
+        walker_stats_output,
+    );
+    if let Some(selection) = &selection {
+        walk_worker.set_selection(selection.clone());
+    }
+    let source_desc: Vec<_> = roots
+        .iter()
+        .map(|root| root.path.to_string_lossy())
+        .collect();
+    progress_info.destination_filesystem(&filesystems.destination);
+    let progress_worker = ProgressWorker::new(
+        &source_desc.join(", "),
+        &destination.to_string_lossy(),
+        progress_input,
+        progress_info,
+    );
+    let delete_worker = options.delete.then(|| {
+        let mut delete_worker =
+            DeleteWorker::new(filesystems, roots, destination, filters, delete_output);
+        if let Some(selection) = &selection {
+            delete_worker.set_selection(selection.clone());
+        }
+        delete_worker
+    });
+
+    let walker_thread = thread::spawn(move || walk_worker.start());
+    let syncer_threads: Vec<_> = sync_workers
+        .into_iter()
+        .map(|sync_worker| {
+            let sync_options = options.clone();
+            thread::spawn(move || sync_worker.start(sync_options))
+        })
+        .collect();
+    let progress_thread = thread::spawn(|| progress_worker.start());
 
-        let syncer_result = syncer_thread
+    let hard_links = walker_thread
+        .join()
+        .map_err(|e| anyhow!("Could not join walker thread: {:?}", e))?;
+
+    // The metadata of the destination comes from the directories whose
+    // contents are synced in it, if any
+    let dest_dirs = roots
+        .iter()
+        .filter(|root| root.rel_path.as_os_str().is_empty() && root.is_dir)
+        .map(|root| (PathBuf::new(), root.path.clone()));
+    let mut syncer_result: Result<BTreeMap<PathBuf, PathBuf>, Error> = Ok(dest_dirs.collect());
+    for syncer_thread in syncer_threads {
+        let result = syncer_thread
             .join()
             .map_err(|e| anyhow!("Could not join syncer thread: {:?}", e))?;
+        syncer_result = syncer_result.and_then(|mut synced_dirs| {
+            synced_dirs.extend(result?);
+            Ok(synced_dirs)
+        });
+    }
 
-        let progress_result = progress_thread
-            .join()
-            .map_err(|e| anyhow!("Could not join progress thread: {:?}", e))?;
+    // Creating hard links, deleting entries and setting directory metadata
+    // must only happen once every source entry has been synced. Directories
+    // are still finalized after a cancellation, since some of their contents
+    // may have changed
+    let synced_dirs = syncer_result.as_ref().ok();
+    let cancelled = control.is_cancelled();
+    if synced_dirs.is_some() && !cancelled {
+        sync_hard_links(
+            &filesystems.destination,
+            destination,
+            &hard_links,
+            &options,
+            &finalize_output,
+        );
+    }
+    if let Some(delete_worker) = delete_worker {
+        if synced_dirs.is_some() && !cancelled {
+            delete_worker.start(&options);
+        }
+    }
+    if let Some(synced_dirs) = synced_dirs {
+        if !options.dry_run {
+            finalize_dirs(
+                filesystems,
+                destination,
+                synced_dirs,
+                &options,
+                &ownership,
+                &finalize_output,
+            );
+        }
+    }
+    if cancelled {
+        let _ = finalize_output.send(ProgressMessage::Cancelled);
+    }
+    drop(finalize_output);
+
+    let progress_result = progress_thread
+        .join()
+        .map_err(|e| anyhow!("Could not join progress thread: {:?}", e))?;
 
-        syncer_result?;
+    syncer_result?;
 
-        Ok(progress_result)
+    Ok(progress_result)
+}
+
+/// Recreate the hard links found in the source, pointing to
+/// the entries synced by the sync workers
+fn sync_hard_links(
+    filesystem: &Arc<dyn Filesystem>,
+    destination: &Path,
+    hard_links: &[HardLink],
+    options: &SyncOptions,
+    output: &Sender<ProgressMessage>,
+) {
+    for hard_link in hard_links {
+        let desc = hard_link.entry.entry.description();
+        let _ = output.send(ProgressMessage::StartSync(desc.to_string()));
+        let dest_path = destination.join(&hard_link.entry.rel_path);
+        let dest_entry = Entry::with_filesystem(filesystem, desc, &dest_path);
+        let target = destination.join(&hard_link.target);
+        let message = match fsops::sync_hard_link(&dest_entry, &target, options) {
+            Ok(outcome) => ProgressMessage::DoneSyncing {
+                entry: desc.to_string(),
+                outcome,
+            },
+            Err(e) => ProgressMessage::SyncError(SyncError::new(desc, ErrorPhase::HardLink, &e)),
+        };
+        let _ = output.send(message);
+    }
+}
+
+/// Set permissions and times of the synced directories. This is done
+/// last, because syncing their contents would change their modification
+/// times, and they may not be writable afterwards
+fn finalize_dirs(
+    filesystems: &Filesystems,
+    destination: &Path,
+    dirs: &BTreeMap<PathBuf, PathBuf>,
+    options: &SyncOptions,
+    ownership: &Ownership,
+    output: &Sender<ProgressMessage>,
+) {
+    // Children are sorted after their parents, so iterating in reverse
+    // order makes sure a directory is not modified after its metadata is set
+    for (rel_path, src_path) in dirs.iter().rev() {
+        let desc = rel_path.to_string_lossy();
+        let src_entry = Entry::with_filesystem(&filesystems.source, &desc, src_path);
+        let dest_path = destination.join(rel_path);
+        let dest_entry = Entry::with_filesystem(&filesystems.destination, &desc, &dest_path);
+        // The directory may have been replaced since it was synced. Setting
+        // permissions follows symlinks, which could lead out of the destination
+        if !dest_entry.is_dir() {
+            continue;
+        }
+        let report = |phase, e: Error| {
+            let _ = output.send(ProgressMessage::SyncError(SyncError::new(&desc, phase, &e)));
+        };
+        // Failing to set one kind of metadata should not
+        // prevent the others from being set
+        #[cfg(unix)]
+        {
+            if let Err(e) = xattrs::copy_xattrs(&src_entry, &dest_entry, options) {
+                report(ErrorPhase::Xattrs, e);
+            }
+        }
+        if let Err(e) = ownership.apply(&src_entry, &dest_entry) {
+            report(ErrorPhase::Ownership, e);
+        }
+        #[cfg(unix)]
+        {
+            if options.preserve_permissions {
+                if let Err(e) = fsops::copy_permissions(&src_entry, &dest_entry) {
+                    report(ErrorPhase::Permissions, e);
+                }
+            }
+        }
+        if options.preserve_times {
+            if let Err(e) = fsops::copy_times(&src_entry, &dest_entry) {
+                report(ErrorPhase::Times, e);
+            }
+        }
     }
 }
@@ -0,0 +1,257 @@
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+
+use anyhow::{Context, Error};
+
+use crate::error::{ErrorPhase, SyncError};
+use crate::filter::{Filters, IgnoreStack};
+use crate::fsops;
+use crate::fsops::SyncOutcome;
+use crate::plan::PlanSelection;
+use crate::progress::ProgressMessage;
+use crate::sync::{Filesystems, SourceRoot, SyncOptions};
+
+pub struct DeleteWorker {
+    output: Sender<ProgressMessage>,
+    filesystems: Filesystems,
+    roots: Vec<SourceRoot>,
+    destination: PathBuf,
+    filters: Filters,
+    selection: Option<Arc<PlanSelection>>,
+}
+
+impl DeleteWorker {
+    pub fn new(
+        filesystems: &Filesystems,
+        roots: &[SourceRoot],
+        destination: &Path,
+        filters: Filters,
+        output: Sender<ProgressMessage>,
+    ) -> DeleteWorker {
+        DeleteWorker {
+            output,
+            filesystems: filesystems.clone(),
+            roots: roots.to_vec(),
+            destination: destination.to_path_buf(),
+            filters,
+            selection: None,
+        }
+    }
+
+    /// Only remove the entries of a plan
+    pub fn set_selection(&mut self, selection: Arc<PlanSelection>) {
+        self.selection = Some(selection);
+    }
+
+    pub fn start(self, opts: &SyncOptions) {
+        // When the contents of a directory are synced directly in the destination,
+        // the whole destination is walked. Otherwise, only the synced directories are
+        let dir_roots: Vec<_> = self.roots.iter().filter(|root| root.is_dir).collect();
+        let walk_all = dir_roots
+            .iter()
+            .any(|root| root.rel_path.as_os_str().is_empty());
+        let mut subdirs: Vec<(PathBuf, IgnoreStack)> = if walk_all {
+            vec![(self.destination.to_path_buf(), IgnoreStack::default())]
+        } else {
+            dir_roots
+                .iter()
+                .map(|root| {
+                    (
+                        self.destination.join(&root.rel_path),
+                        IgnoreStack::default(),
+                    )
+                })
+                .collect()
+        };
+        subdirs.retain(|(subdir, _)| self.is_real_dir(subdir));
+
+        while let Some((subdir, parent_ignores)) = subdirs.pop() {
+            let rel_subdir = fsops::get_rel_path(&subdir, &self.destination);
+            // Entries ignored in the source must not be deleted, so when
+            // in doubt, leave the whole directory alone
+            let source = self.filesystems.source.as_ref();
+            let src_subdir = self
+                .source_paths(&rel_subdir)
+                .find(|path| source.metadata(path).is_ok_and(|m| m.is_dir()));
+            let ignores = match src_subdir {
+                Some(src_subdir) => {
+                    match self.filters.read_ignore_files(
+                        source,
+                        &parent_ignores,
+                        &src_subdir,
+                        &rel_subdir,
+                    ) {
+                        Ok(ignores) => ignores,
+                        Err(e) => {
+                            self.report_error(&subdir, e);
+                            continue;
+                        }
+                    }
+                }
+                None => parent_ignores,
+            };
+            let names = match self.filesystems.destination.read_dir(&subdir) {
+                Ok(names) => names,
+                Err(e) => {
+                    self.report_error(&subdir, Error::new(e).context("Could not read directory"));
+                    continue;
+                }
+            };
+            for name in names {
+                let path = subdir.join(name);
+                if let Some(subdir) = self.process(&path, &ignores, opts) {
+                    subdirs.push((subdir, ignores.clone()));
+                }
+            }
+        }
+    }
+
+    /// Paths of the source entries synced to `rel_path` in the destination
+    fn source_paths<'a>(&'a self, rel_path: &'a Path) -> impl Iterator<Item = PathBuf> + 'a {
+        self.roots.iter().filter_map(move |root| {
+            let rel_in_root = rel_path.strip_prefix(&root.rel_path).ok()?;
+            if rel_in_root.as_os_str().is_empty() {
+                Some(root.path.clone())
+            } else {
+                Some(root.path.join(rel_in_root))
+            }
+        })
+    }
+
+    /// Remove `dest_path` if it does not exist in any of the sources.
+    /// Return the path if it needs to be walked further
+    fn process(
+        &self,
+        dest_path: &Path,
+        ignores: &IgnoreStack,
+        opts: &SyncOptions,
+    ) -> Option<PathBuf> {
+        let rel_path = fsops::get_rel_path(dest_path, &self.destination);
+        let dest_is_dir = self.is_real_dir(dest_path);
+        // Excluded entries are never deleted
+        if self.filters.is_excluded(&rel_path, dest_is_dir, ignores) {
+            return None;
+        }
+        // What a missing source was synced to is kept, the walker
+        // reports the source as an error
+        let mut in_source = self.roots.iter().any(|root| root.rel_path == rel_path);
+        let mut src_is_dir = false;
+        for src_path in self.source_paths(&rel_path) {
+            match self.filesystems.source.symlink_metadata(&src_path) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Ok(src_meta) => src_is_dir |= src_meta.is_dir(),
+                // Only delete when we know for sure the source is gone
+                Err(_) => (),
+            }
+            in_source = true;
+        }
+        if let Some(selection) = &self.selection {
+            if !in_source && selection.is_deleted(&rel_path) {
+                self.remove(dest_path, ignores, opts);
+            }
+            let walk = src_is_dir && dest_is_dir && selection.contains_deleted(&rel_path);
+            return walk.then(|| dest_path.to_path_buf());
+        }
+        if !in_source {
+            self.remove(dest_path, ignores, opts);
+            return None;
+        }
+        (src_is_dir && dest_is_dir).then(|| dest_path.to_path_buf())
+    }
+
+    /// Remove `dest_path` and its contents, except for the excluded entries.
+    /// Return true if some of them were kept, along with the directories containing them
+    fn remove(&self, dest_path: &Path, ignores: &IgnoreStack, opts: &SyncOptions) -> bool {
+        let destination = self.filesystems.destination.as_ref();
+        if self.is_real_dir(dest_path) {
+            // The directory is gone from the source, so its ignore files
+            // can only be read from the destination
+            let rel_dir = fsops::get_rel_path(dest_path, &self.destination);
+            let ignores = self
+                .filters
+                .read_ignore_files(destination, ignores, dest_path, &rel_dir);
+            let ignores = match ignores {
+                Ok(ignores) => ignores,
+                Err(e) => {
+                    // Entries that may be ignored must not be deleted
+                    self.report_error(dest_path, e);
+                    return true;
+                }
+            };
+            let names = match destination.read_dir(dest_path) {
+                Ok(names) => names,
+                Err(e) => {
+                    self.report_error(dest_path, Error::new(e).context("Could not read directory"));
+                    return false;
+                }
+            };
+            let mut kept = false;
+            for name in names {
+                let path = dest_path.join(name);
+                let rel_path = fsops::get_rel_path(&path, &self.destination);
+                if self
+                    .filters
+                    .is_excluded(&rel_path, self.is_real_dir(&path), &ignores)
+                {
+                    kept = true;
+                    continue;
+                }
+                if let Some(selection) = &self.selection {
+                    // Removing the directory then fails, which is reported
+                    if !selection.is_deleted(&rel_path) {
+                        continue;
+                    }
+                }
+                kept |= self.remove(&path, &ignores, opts);
+            }
+            if kept {
+                return true;
+            }
+            let outcome = if opts.dry_run {
+                Ok(())
+            } else {
+                destination.remove_dir(dest_path)
+            };
+            self.report(dest_path, outcome);
+        } else {
+            let outcome = if opts.dry_run {
+                Ok(())
+            } else {
+                destination.remove_file(dest_path)
+            };
+            self.report(dest_path, outcome);
+        }
+        false
+    }
+
+    fn is_real_dir(&self, path: &Path) -> bool {
+        // Do not follow symlinks: a link to a directory is removed, not walked
+        self.filesystems
+            .destination
+            .symlink_metadata(path)
+            .is_ok_and(|m| m.is_dir())
+    }
+
+    fn report(&self, dest_path: &Path, outcome: io::Result<()>) {
+        match outcome.with_context(|| format!("Could not remove '{}'", dest_path.display())) {
+            Ok(()) => {
+                let rel_path = fsops::get_rel_path(dest_path, &self.destination);
+                let _ = self.output.send(ProgressMessage::DoneSyncing {
+                    entry: rel_path.to_string_lossy().to_string(),
+                    outcome: SyncOutcome::Deleted,
+                });
+            }
+            Err(e) => self.report_error(dest_path, e),
+        }
+    }
+
+    fn report_error(&self, dest_path: &Path, error: Error) {
+        let rel_path = fsops::get_rel_path(dest_path, &self.destination);
+        let entry = rel_path.to_string_lossy();
+        let error = SyncError::new(&entry, ErrorPhase::Delete, &error);
+        let _ = self.output.send(ProgressMessage::SyncError(error));
+    }
+}
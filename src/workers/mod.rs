@@ -1,7 +1,11 @@
+mod delete_worker;
 mod progress_worker;
 mod sync_worker;
 mod walk_worker;
 
+pub use self::delete_worker::DeleteWorker;
 pub use self::progress_worker::ProgressWorker;
 pub use self::sync_worker::SyncWorker;
+pub use self::walk_worker::HardLink;
+pub use self::walk_worker::SourceEntry;
 pub use self::walk_worker::WalkWorker;
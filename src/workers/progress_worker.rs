@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::sync::mpsc::Receiver;
 use std::time::Instant;
 
@@ -7,27 +8,35 @@ use crate::sync::Stats;
 pub struct ProgressWorker {
     input: Receiver<ProgressMessage>,
     progress_info: Box<dyn ProgressInfo + Send>,
+    source: String,
+    destination: String,
 }
 
 impl ProgressWorker {
     pub fn new(
+        source: &str,
+        destination: &str,
         input: Receiver<ProgressMessage>,
         progress_info: Box<dyn ProgressInfo + Send>,
     ) -> ProgressWorker {
         ProgressWorker {
             input,
             progress_info,
+            source: source.to_string(),
+            destination: destination.to_string(),
         }
     }
 
     pub fn start(mut self) -> Stats {
         let mut stats = Stats::new();
-        let mut file_done = 0;
-        let mut current_file = String::from("");
+        // Number of bytes done for each file being synced. There can be
+        // more than one when several sync workers are running
+        let mut files_done: HashMap<String, usize> = HashMap::new();
         let mut index = 0;
         let mut total_done = 0;
         let now = Instant::now();
         stats.start();
+        self.progress_info.start(&self.source, &self.destination);
         for progress in self.input.iter() {
             match progress {
                 ProgressMessage::Todo {
@@ -39,31 +48,42 @@ impl ProgressWorker {
                 }
                 ProgressMessage::StartSync(x) => {
                     self.progress_info.new_file(&x);
-                    current_file = x;
+                    files_done.insert(x, 0);
                     index += 1;
                 }
-                ProgressMessage::DoneSyncing(x) => {
+                ProgressMessage::DoneSyncing { entry, outcome } => {
                     self.progress_info.done_syncing();
-                    stats.add_outcome(&x);
-                    file_done = 0;
+                    self.progress_info.synced(&entry, &outcome);
+                    stats.add_outcome(&outcome);
+                    files_done.remove(&entry);
                 }
-                ProgressMessage::SyncError { entry, details } => {
-                    self.progress_info.error(&entry, &details);
+                ProgressMessage::SyncError(error) => {
+                    self.progress_info.error(&error);
                     stats.add_error();
+                    files_done.remove(&error.entry);
                 }
-                ProgressMessage::Syncing { done, size, .. } => {
-                    file_done += done;
+                ProgressMessage::Cancelled => stats.cancelled = true,
+                ProgressMessage::Syncing {
+                    description,
+                    done,
+                    size,
+                } => {
+                    let file_done = files_done.entry(description.clone()).or_insert(0);
+                    *file_done += done;
                     total_done += done;
                     let elapsed = now.elapsed().as_secs() as usize;
-                    let eta = ((elapsed * stats.total_size) / total_done) - elapsed;
+                    // The walker may not have reported the total size yet
+                    let eta = (elapsed * stats.total_size)
+                        .checked_div(total_done)
+                        .map_or(0, |total| total.saturating_sub(elapsed));
                     let detailed_progress = Progress {
-                        file_done,
+                        file_done: *file_done,
                         file_size: size,
                         total_done,
                         total_size: stats.total_size,
                         index,
                         num_files: stats.num_files as usize,
-                        current_file: current_file.clone(),
+                        current_file: description,
                         eta,
                     };
                     self.progress_info.progress(&detailed_progress);
@@ -1,77 +1,192 @@
-use std::fs;
+use std::collections::{BTreeMap, HashSet};
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
 
-use anyhow::{Context, Error};
+use anyhow::{anyhow, Context, Error};
 
+use crate::control::SyncControl;
 use crate::entry::Entry;
+use crate::error::{ErrorPhase, SyncError};
+use crate::filesystem::Filesystem;
 use crate::fsops;
 use crate::fsops::SyncOutcome;
+use crate::ownership::Ownership;
 use crate::progress::ProgressMessage;
 use crate::sync::SyncOptions;
+use crate::workers::SourceEntry;
+#[cfg(unix)]
+use crate::xattrs;
 
+/// Several sync workers can run at the same time, sharing the
+/// same input queue
 pub struct SyncWorker {
-    input: Receiver<Entry>,
+    input: Arc<Mutex<Receiver<SourceEntry>>>,
     output: Sender<ProgressMessage>,
-    source: PathBuf,
+    filesystem: Arc<dyn Filesystem>,
     destination: PathBuf,
+    cleaned_dirs: Arc<Mutex<HashSet<PathBuf>>>,
+    ownership: Ownership,
+    control: Arc<SyncControl>,
 }
 
 impl SyncWorker {
     pub fn new(
-        source: &Path,
+        filesystem: Arc<dyn Filesystem>,
         destination: &Path,
-        input: Receiver<Entry>,
+        input: Arc<Mutex<Receiver<SourceEntry>>>,
+        cleaned_dirs: Arc<Mutex<HashSet<PathBuf>>>,
+        ownership: Ownership,
+        control: Arc<SyncControl>,
         output: Sender<ProgressMessage>,
     ) -> SyncWorker {
         SyncWorker {
-            source: source.to_path_buf(),
+            filesystem,
             destination: destination.to_path_buf(),
             input,
             output,
+            cleaned_dirs,
+            ownership,
+            control,
         }
     }
 
-    pub fn start(self, opts: SyncOptions) -> Result<(), Error> {
-        for entry in self.input.iter() {
-            let sync_outcome = self.sync(&entry, opts);
+    fn next_entry(&self) -> Option<SourceEntry> {
+        // The lock is released as soon as an entry is received, so
+        // that other workers can get theirs while this one is syncing
+        let input = self.input.lock().ok()?;
+        input.recv().ok()
+    }
+
+    /// Sync every entry received, and return the relative paths of the
+    /// synced directories along with their source paths, so that their
+    /// metadata can be set once everything else is done. Stop early if
+    /// the sync is cancelled
+    pub fn start(self, opts: SyncOptions) -> Result<BTreeMap<PathBuf, PathBuf>, Error> {
+        if !opts.dry_run {
+            self.filesystem
+                .create_dir_all(&self.destination)
+                .with_context(|| format!("Could not create '{}'", self.destination.display()))?;
+        }
+        let mut synced_dirs = BTreeMap::new();
+        while let Some(source_entry) = self.next_entry() {
+            if self.control.checkpoint().is_err() {
+                break;
+            }
+            let SourceEntry { entry, rel_path } = &source_entry;
+            let parent_rel_path = rel_path
+                .parent()
+                .expect("dest directory should have a parent");
+            if !opts.inplace && !opts.dry_run {
+                self.remove_temp_files(parent_rel_path)?;
+            }
+            let sync_outcome = self.sync(entry, rel_path, &opts);
+            // Only directories that were created or already existed get their
+            // metadata set, not the files or links that may be in the way
+            if entry.is_dir() && sync_outcome.is_ok() {
+                synced_dirs.insert(rel_path.clone(), entry.path().clone());
+            }
             let progress_message = match sync_outcome {
-                Ok(s) => ProgressMessage::DoneSyncing(s),
-                Err(e) => ProgressMessage::SyncError {
+                Ok(outcome) => ProgressMessage::DoneSyncing {
                     entry: entry.description().to_string(),
-                    details: format!("{:#}", e),
+                    outcome,
                 },
+                // The entry is left as it was, which is not an error
+                Err(_) if self.control.is_cancelled() => break,
+                Err(e) => ProgressMessage::SyncError(e),
             };
             self.output.send(progress_message)?;
         }
+        Ok(synced_dirs)
+    }
+
+    /// Remove leftover temporary files from the destination directory at
+    /// `rel_path`, unless this was already done by one of the workers
+    fn remove_temp_files(&self, rel_path: &Path) -> Result<(), Error> {
+        // Keep the lock while removing the files, so that no other worker
+        // can write a temporary file in the same directory meanwhile
+        let mut cleaned_dirs = self
+            .cleaned_dirs
+            .lock()
+            .map_err(|_| anyhow!("Could not lock the list of cleaned directories"))?;
+        if !cleaned_dirs.insert(rel_path.to_path_buf()) {
+            return Ok(());
+        }
+        let dest_dir = self.destination.join(rel_path);
+        if let Err(e) = fsops::remove_temp_files(self.filesystem.as_ref(), &dest_dir) {
+            let error = SyncError::new(&rel_path.to_string_lossy(), ErrorPhase::Write, &e);
+            self.output.send(ProgressMessage::SyncError(error))?;
+        }
         Ok(())
     }
 
+    fn report_error(&self, error: SyncError) {
+        // If the progress worker is gone, the next send in start() fails
+        let _ = self.output.send(ProgressMessage::SyncError(error));
+    }
+
     fn create_missing_dest_dirs(&self, rel_path: &Path) -> Result<(), Error> {
         let parent_rel_path = rel_path
             .parent()
             .expect("dest directory should have a parent");
         let to_create = self.destination.join(parent_rel_path);
-        fs::create_dir_all(&to_create)
+        self.filesystem
+            .create_dir_all(&to_create)
             .with_context(|| format!("Could not create '{}'", to_create.display()))?;
         Ok(())
     }
 
-    fn sync(&self, src_entry: &Entry, opts: SyncOptions) -> Result<SyncOutcome, Error> {
-        let rel_path = fsops::get_rel_path(src_entry.path(), &self.source);
-        self.create_missing_dest_dirs(&rel_path)?;
+    fn sync(
+        &self,
+        src_entry: &Entry,
+        rel_path: &Path,
+        opts: &SyncOptions,
+    ) -> Result<SyncOutcome, SyncError> {
         let desc = rel_path.to_string_lossy();
+        let error = |phase, e: Error| SyncError::new(&desc, phase, &e);
+        if !opts.dry_run {
+            self.create_missing_dest_dirs(rel_path)
+                .map_err(|e| error(ErrorPhase::Write, e))?;
+        }
 
-        let dest_path = self.destination.join(&rel_path);
-        let dest_entry = Entry::new(&desc, &dest_path);
-        let outcome = fsops::sync_entries(&self.output, src_entry, &dest_entry)?;
+        let dest_path = self.destination.join(rel_path);
+        let dest_entry = Entry::with_filesystem(&self.filesystem, &desc, &dest_path);
+        let phase = if src_entry.is_link() == Some(true) {
+            ErrorPhase::Symlink
+        } else {
+            ErrorPhase::Write
+        };
+        let outcome =
+            fsops::sync_entries(&self.output, &self.control, src_entry, &dest_entry, opts)
+                .map_err(|e| error(phase, e))?;
+        // Directory metadata is set once their contents are synced
+        if opts.dry_run || src_entry.is_dir() {
+            return Ok(outcome);
+        }
+        // Failing to copy extended attributes or ownership is reported,
+        // but the entry is still considered synced
+        #[cfg(unix)]
+        {
+            if let Err(e) = xattrs::copy_xattrs(src_entry, &dest_entry, opts) {
+                self.report_error(error(ErrorPhase::Xattrs, e));
+            }
+        }
+        // Changing ownership may clear setuid and setgid bits, so this must
+        // be done before copying permissions
+        if let Err(e) = self.ownership.apply(src_entry, &dest_entry) {
+            self.report_error(error(ErrorPhase::Ownership, e));
+        }
         #[cfg(unix)]
         {
             if opts.preserve_permissions {
-                fsops::copy_permissions(src_entry, &dest_entry)?;
+                fsops::copy_permissions(src_entry, &dest_entry)
+                    .map_err(|e| error(ErrorPhase::Permissions, e))?;
             }
         }
+        if opts.preserve_times {
+            fsops::copy_times(src_entry, &dest_entry).map_err(|e| error(ErrorPhase::Times, e))?;
+        }
         Ok(outcome)
     }
 }
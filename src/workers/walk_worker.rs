@@ -1,91 +1,261 @@
-use std::fs;
-use std::fs::DirEntry;
+use std::collections::HashMap;
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::mpsc::Sender;
+use std::sync::Arc;
 
-use anyhow::{bail, Context, Error};
+use anyhow::{anyhow, bail, Context, Error};
 
+use crate::control::{self, SyncControl};
 use crate::entry::Entry;
-use crate::fsops;
+use crate::error::{ErrorPhase, SyncError};
+use crate::filesystem::Filesystem;
+use crate::filter::{Filters, IgnoreStack};
+use crate::plan::PlanSelection;
 use crate::progress::ProgressMessage;
+use crate::sync::SourceRoot;
+
+/// An entry found in the source, and where to sync it
+pub struct SourceEntry {
+    pub entry: Entry,
+    /// Path of the entry in the destination, relative to the destination root
+    pub rel_path: PathBuf,
+}
+
+/// A source file that has the same inode as a file found earlier
+/// during the walk
+pub struct HardLink {
+    pub entry: SourceEntry,
+    /// Relative path in the destination of the first file found with this inode
+    pub target: PathBuf,
+}
+
+#[derive(Default)]
+struct WalkState {
+    num_files: u64,
+    total_size: u64,
+    // Relative path of the first file found for each (device, inode) pair
+    inodes: HashMap<(u64, u64), PathBuf>,
+    hard_links: Vec<HardLink>,
+}
 
 pub struct WalkWorker {
-    entry_output: Sender<Entry>,
+    entry_output: Sender<SourceEntry>,
     progress_output: Sender<ProgressMessage>,
-    source: PathBuf,
+    filesystem: Arc<dyn Filesystem>,
+    roots: Vec<SourceRoot>,
+    filters: Filters,
+    hard_links: bool,
+    selection: Option<Arc<PlanSelection>>,
+    control: Arc<SyncControl>,
 }
 
 impl WalkWorker {
     pub fn new(
-        source: &Path,
-        entry_output: Sender<Entry>,
+        filesystem: Arc<dyn Filesystem>,
+        roots: &[SourceRoot],
+        filters: Filters,
+        hard_links: bool,
+        control: Arc<SyncControl>,
+        entry_output: Sender<SourceEntry>,
         progress_output: Sender<ProgressMessage>,
     ) -> WalkWorker {
         WalkWorker {
             entry_output,
             progress_output,
-            source: source.to_path_buf(),
+            filesystem,
+            roots: roots.to_vec(),
+            filters,
+            hard_links,
+            selection: None,
+            control,
         }
     }
 
-    fn walk(&self) -> Result<(), Error> {
-        let mut num_files = 0;
-        let mut total_size = 0;
-        let mut subdirs: Vec<PathBuf> = vec![self.source.to_path_buf()];
-        while let Some(subdir) = subdirs.pop() {
-            // We just checked that subdirs is *not* empty, so calling pop() is safe
-
-            let entries = fs::read_dir(&subdir).with_context(|| {
-                format!(
-                    "While walking source, could not read directory '{}'",
-                    subdir.display()
-                )
-            })?;
-            for entry in entries {
-                let entry = entry.with_context(|| {
-                    format!(
-                        "While walking source dir, could not read subdir: '{}'",
-                        subdir.display()
-                    )
-                })?;
-                let path = entry.path();
-                if path.is_dir() {
-                    subdirs.push(path);
-                } else {
-                    let meta = self.process_file(&entry)?;
-                    num_files += 1;
-                    total_size += meta.len();
-                    let sent = self.progress_output.send(ProgressMessage::Todo {
-                        num_files,
-                        total_size: total_size as usize,
-                    });
-                    if sent.is_err() {
-                        bail!("stats output chan is closed");
+    /// Only send the entries of a plan, and the directories containing them.
+    /// The whole source is still walked, so that hard links are found
+    pub fn set_selection(&mut self, selection: Arc<PlanSelection>) {
+        self.selection = Some(selection);
+    }
+
+    /// Walk the sources, sending entries to the sync workers. Files having the
+    /// same inode as a file already sent are not sent, but put in `state.hard_links` instead.
+    /// Entries that cannot be read are reported and skipped: only failing to
+    /// talk to the other workers, or cancelling the sync, stops the walk
+    fn walk(&self, state: &mut WalkState) -> Result<(), Error> {
+        for root in &self.roots {
+            self.walk_root(root, state)?;
+        }
+        Ok(())
+    }
+
+    fn walk_root(&self, root: &SourceRoot, state: &mut WalkState) -> Result<(), Error> {
+        let mut subdirs: Vec<(PathBuf, PathBuf, IgnoreStack)> = vec![];
+        if root.rel_path.as_os_str().is_empty() {
+            // Only the contents of the root are synced
+            subdirs.push((root.path.clone(), PathBuf::new(), IgnoreStack::default()));
+        } else {
+            let is_dir = match self.filesystem.symlink_metadata(&root.path) {
+                Ok(metadata) => metadata.is_dir(),
+                Err(e) => {
+                    let context = format!("Could not read metadata of '{}'", root.path.display());
+                    self.report_error(&root.rel_path, Error::new(e).context(context));
+                    return Ok(());
+                }
+            };
+            let ignores = IgnoreStack::default();
+            if self.process_entry(&root.path, &root.rel_path, is_dir, &ignores, state)? {
+                subdirs.push((root.path.clone(), root.rel_path.clone(), ignores));
+            }
+        }
+
+        while let Some((subdir, rel_subdir, parent_ignores)) = subdirs.pop() {
+            let ignores = match self.filters.read_ignore_files(
+                self.filesystem.as_ref(),
+                &parent_ignores,
+                &subdir,
+                &rel_subdir,
+            ) {
+                Ok(ignores) => ignores,
+                Err(e) => {
+                    // Without its ignore files, we cannot tell which entries
+                    // of the directory should be synced
+                    self.report_error(&rel_subdir, e);
+                    continue;
+                }
+            };
+
+            let names = match self.filesystem.read_dir(&subdir) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    let context = format!("Could not read directory '{}'", subdir.display());
+                    self.report_error(&rel_subdir, Error::new(e).context(context));
+                    continue;
+                }
+            };
+            for name in names {
+                let path = subdir.join(&name);
+                let rel_path = rel_subdir.join(&name);
+                // Do not follow symlinks: links to directories are synced as links
+                let is_dir = match self.filesystem.symlink_metadata(&path) {
+                    Ok(metadata) => metadata.is_dir(),
+                    Err(e) => {
+                        let context = format!("Could not read file type of '{}'", path.display());
+                        self.report_error(&rel_path, Error::new(e).context(context));
+                        continue;
                     }
+                };
+                if self.process_entry(&path, &rel_path, is_dir, &ignores, state)? {
+                    subdirs.push((path, rel_path, ignores.clone()));
                 }
             }
         }
         Ok(())
     }
 
-    fn process_file(&self, entry: &DirEntry) -> Result<fs::Metadata, Error> {
-        let rel_path = fsops::get_rel_path(&entry.path(), &self.source);
+    /// Send the entry at `path` to the sync workers, unless it is excluded or a hard link.
+    /// Return true if it is a directory that must be walked
+    fn process_entry(
+        &self,
+        path: &Path,
+        rel_path: &Path,
+        is_dir: bool,
+        ignores: &IgnoreStack,
+        state: &mut WalkState,
+    ) -> Result<bool, Error> {
+        // Excluded directories are skipped along with their contents
+        if self.filters.is_excluded(rel_path, is_dir, ignores) {
+            return Ok(false);
+        }
         let desc = rel_path.to_string_lossy();
-        let src_entry = Entry::new(&desc, &entry.path());
-        let metadata = src_entry
-            .metadata()
-            .with_context(|| format!("Could not read metadata from {:?}", entry.path()))?;
-        self.entry_output
-            .send(src_entry.clone())
-            .with_context(|| "When walking source dir: could not send entry to progress worker")?;
-        Ok(metadata.clone())
+        let entry = Entry::with_filesystem(&self.filesystem, &desc, path);
+        let size = match entry.metadata() {
+            Some(metadata) => metadata.len(),
+            None => {
+                // The entry vanished or cannot be read: the sync worker
+                // would not be able to sync it either
+                let error = anyhow!("Could not read metadata from '{}'", path.display());
+                self.report_error(rel_path, error);
+                return Ok(false);
+            }
+        };
+        let source_entry = SourceEntry {
+            entry,
+            rel_path: rel_path.to_path_buf(),
+        };
+        let selected = self
+            .selection
+            .as_ref()
+            .is_none_or(|selection| selection.is_synced(rel_path));
+        if !selected {
+            return Ok(is_dir);
+        }
+        // Only entries that are synced can be linked to
+        let hard_link_target = self.hard_link_target(&mut state.inodes, &source_entry);
+        self.control.checkpoint()?;
+        state.num_files += 1;
+        if let Some(target) = hard_link_target {
+            state.hard_links.push(HardLink {
+                entry: source_entry,
+                target,
+            });
+        } else {
+            // Directories are sent before their contents, so that they
+            // are created first
+            self.entry_output.send(source_entry).with_context(|| {
+                "When walking source dir: could not send entry to progress worker"
+            })?;
+            if !is_dir {
+                state.total_size += size;
+            }
+        }
+        let sent = self.progress_output.send(ProgressMessage::Todo {
+            num_files: state.num_files,
+            total_size: state.total_size as usize,
+        });
+        if sent.is_err() {
+            bail!("stats output chan is closed");
+        }
+        Ok(is_dir)
     }
 
-    pub fn start(&self) {
-        let outcome = &self.walk();
-        if outcome.is_err() {
-            // Send err to output
+    fn report_error(&self, rel_path: &Path, error: Error) {
+        let entry = rel_path.to_string_lossy();
+        let error = SyncError::new(&entry, ErrorPhase::Walk, &error);
+        let _ = self.progress_output.send(ProgressMessage::SyncError(error));
+    }
+
+    /// If `source_entry` is a file with the same inode as a file found earlier,
+    /// return the relative path of the earlier one
+    fn hard_link_target(
+        &self,
+        inodes: &mut HashMap<(u64, u64), PathBuf>,
+        source_entry: &SourceEntry,
+    ) -> Option<PathBuf> {
+        let meta = source_entry.entry.metadata()?;
+        // Filesystems without inodes, such as on Windows, report a single link
+        if !self.hard_links || !meta.is_file() || meta.nlink < 2 {
+            return None;
+        }
+        match inodes.entry((meta.dev, meta.ino)) {
+            std::collections::hash_map::Entry::Occupied(first) => Some(first.get().clone()),
+            std::collections::hash_map::Entry::Vacant(vacant) => {
+                vacant.insert(source_entry.rel_path.clone());
+                None
+            }
+        }
+    }
+
+    /// Return the hard links found, which must be synced once
+    /// the entries they point to are
+    pub fn start(&self) -> Vec<HardLink> {
+        let mut state = WalkState::default();
+        if let Err(e) = self.walk(&mut state) {
+            // Once cancelled, the sync workers may be gone too
+            if !control::is_cancellation(&e) && !self.control.is_cancelled() {
+                self.report_error(Path::new(""), e);
+            }
         }
+        state.hard_links
     }
 }
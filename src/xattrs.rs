@@ -0,0 +1,190 @@
+//! xattrs
+//!
+//! Copy extended attributes (`user.*`, SELinux labels, ...) and POSIX ACLs,
+//! which are stored as extended attributes in the `system` namespace.
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+
+use anyhow::{bail, Context, Error};
+
+use crate::entry::Entry;
+use crate::sync::SyncOptions;
+
+/// Names of the extended attributes holding POSIX ACLs
+pub const ACL_XATTRS: [&str; 2] = ["system.posix_acl_access", "system.posix_acl_default"];
+
+fn is_acl(name: &OsStr) -> bool {
+    ACL_XATTRS.iter().any(|acl| name == *acl)
+}
+
+/// Copy the extended attributes of `src` to `dest`: ACLs if `opts.acls` is set, and
+/// all the others if `opts.xattrs` is set. Selected attributes that only exist in `dest`
+/// are removed. Symlinks are not followed.
+///
+/// Every attribute is tried, even if some of them cannot be copied, for instance
+/// because the destination filesystem does not support them
+pub fn copy_xattrs(src: &Entry, dest: &Entry, opts: &SyncOptions) -> Result<(), Error> {
+    if !opts.xattrs && !opts.acls {
+        return Ok(());
+    }
+    if !src.filesystem().is_local() || !dest.filesystem().is_local() {
+        bail!("Extended attributes can only be copied on the local filesystem");
+    }
+    let is_selected = |name: &OsStr| {
+        if is_acl(name) {
+            opts.acls
+        } else {
+            opts.xattrs
+        }
+    };
+    let src_names: Vec<_> = list(src)?.into_iter().filter(|n| is_selected(n)).collect();
+    let dest_names: Vec<_> = list(dest)?.into_iter().filter(|n| is_selected(n)).collect();
+
+    let mut errors = vec![];
+    for name in &src_names {
+        if let Err(e) = copy_xattr(src, dest, name) {
+            errors.push(format!("{}: {}", name.to_string_lossy(), e));
+        }
+    }
+    for name in dest_names.iter().filter(|n| !src_names.contains(n)) {
+        if let Err(e) = xattr::remove(dest.path(), name) {
+            errors.push(format!("{}: {}", name.to_string_lossy(), e));
+        }
+    }
+    if !errors.is_empty() {
+        bail!(
+            "Could not copy extended attributes to '{}': {}",
+            dest.description(),
+            errors.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn list(entry: &Entry) -> Result<Vec<OsString>, Error> {
+    match xattr::list(entry.path()) {
+        Ok(names) => Ok(names.collect()),
+        // Nothing to copy from or to remove on filesystems without extended attributes
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => Ok(vec![]),
+        Err(e) => Err(e).with_context(|| {
+            format!(
+                "Could not list extended attributes of '{}'",
+                entry.description()
+            )
+        }),
+    }
+}
+
+fn copy_xattr(src: &Entry, dest: &Entry, name: &OsStr) -> io::Result<()> {
+    let value = match xattr::get(src.path(), name)? {
+        Some(value) => value,
+        // Removed since it was listed
+        None => return Ok(()),
+    };
+    if xattr::get(dest.path(), name).ok().flatten().as_ref() == Some(&value) {
+        return Ok(());
+    }
+    xattr::set(dest.path(), name, &value)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    fn setup(tmp_path: &Path) -> Result<(Entry, Entry), std::io::Error> {
+        let src = tmp_path.join("src.txt");
+        let dest = tmp_path.join("dest.txt");
+        std::fs::write(&src, "src")?;
+        std::fs::write(&dest, "dest")?;
+        Ok((Entry::new("src.txt", &src), Entry::new("dest.txt", &dest)))
+    }
+
+    // Binary representation of the ACL 'u::rw-,u:1234:r--,g::r--,m::r--,o::r--'
+    fn acl() -> Vec<u8> {
+        let entries: [(u16, u16, u32); 5] = [
+            (0x01, 6, u32::MAX),
+            (0x02, 4, 1234),
+            (0x04, 4, u32::MAX),
+            (0x10, 4, u32::MAX),
+            (0x20, 4, u32::MAX),
+        ];
+        let mut acl = 2u32.to_le_bytes().to_vec();
+        for (tag, perm, id) in entries {
+            acl.extend_from_slice(&tag.to_le_bytes());
+            acl.extend_from_slice(&perm.to_le_bytes());
+            acl.extend_from_slice(&id.to_le_bytes());
+        }
+        acl
+    }
+
+    #[test]
+    fn copy_user_xattrs() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new()?;
+        let (src, dest) = setup(tmp_dir.path())?;
+        xattr::set(src.path(), "user.color", b"blue")?;
+        xattr::set(dest.path(), "user.stale", b"yes")?;
+        let opts = SyncOptions {
+            xattrs: true,
+            ..Default::default()
+        };
+
+        copy_xattrs(&src, &dest, &opts).unwrap();
+
+        assert_eq!(
+            xattr::get(dest.path(), "user.color")?,
+            Some(b"blue".to_vec())
+        );
+        assert_eq!(xattr::get(dest.path(), "user.stale")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn xattrs_are_not_copied_by_default() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new()?;
+        let (src, dest) = setup(tmp_dir.path())?;
+        xattr::set(src.path(), "user.color", b"blue")?;
+
+        copy_xattrs(&src, &dest, &SyncOptions::default()).unwrap();
+
+        assert_eq!(xattr::get(dest.path(), "user.color")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn copy_acls_only() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new()?;
+        let (src, dest) = setup(tmp_dir.path())?;
+        xattr::set(src.path(), "user.color", b"blue")?;
+        xattr::set(src.path(), ACL_XATTRS[0], &acl())?;
+        let opts = SyncOptions {
+            acls: true,
+            ..Default::default()
+        };
+
+        copy_xattrs(&src, &dest, &opts).unwrap();
+
+        assert_eq!(xattr::get(dest.path(), ACL_XATTRS[0])?, Some(acl()));
+        assert_eq!(xattr::get(dest.path(), "user.color")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn report_errors() -> Result<(), std::io::Error> {
+        let tmp_dir = TempDir::new()?;
+        let (src, _) = setup(tmp_dir.path())?;
+        xattr::set(src.path(), "user.color", b"blue")?;
+        let dest = Entry::new("missing.txt", &tmp_dir.path().join("missing.txt"));
+        let opts = SyncOptions {
+            xattrs: true,
+            ..Default::default()
+        };
+
+        let err = copy_xattrs(&src, &dest, &opts).unwrap_err();
+
+        assert!(err.to_string().contains("missing.txt"));
+        Ok(())
+    }
+}
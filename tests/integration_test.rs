@@ -7,6 +7,7 @@ use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
+use std::sync::{Arc, Mutex};
 
 use filetime::FileTime;
 use tempfile::TempDir;
@@ -68,6 +69,12 @@ fn make_recent(path: &Path) -> io::Result<()> {
     Ok(())
 }
 
+fn mtime(path: &Path) -> FileTime {
+    let metadata = fs::symlink_metadata(path)
+        .unwrap_or_else(|e| panic!("Could not get metadata of {:?}: {}", path, e));
+    FileTime::from_last_modification_time(&metadata)
+}
+
 struct DummyProgressInfo {}
 impl ProgressInfo for DummyProgressInfo {}
 
@@ -75,6 +82,7 @@ fn new_test_syncer(src: &Path, dest: &Path) -> rusync::Syncer {
     let dummy_progress_info = DummyProgressInfo {};
     let options = rusync::SyncOptions {
         preserve_permissions: true,
+        ..Default::default()
     };
     rusync::Syncer::new(src, dest, options, Box::new(dummy_progress_info))
 }
@@ -132,6 +140,7 @@ fn do_not_preserve_permissions() -> Result<(), std::io::Error> {
     let (src_path, dest_path) = setup_test(tmp_dir.path());
     let options = rusync::SyncOptions {
         preserve_permissions: false,
+        ..Default::default()
     };
     let syncer = rusync::Syncer::new(
         &src_path,
@@ -166,6 +175,12 @@ fn rewrite_partially_written_files() -> Result<(), std::io::Error> {
     Ok(())
 }
 
+fn make_read_only(path: &Path) -> io::Result<()> {
+    let mut perms = fs::metadata(path)?.permissions();
+    perms.set_readonly(true);
+    fs::set_permissions(path, perms)
+}
+
 #[test]
 fn dest_read_only() -> Result<(), std::io::Error> {
     let tmp_dir = TempDir::new()?;
@@ -174,17 +189,63 @@ fn dest_read_only() -> Result<(), std::io::Error> {
 
     let dest_top = dest_path.join("top.txt");
     fs::write(&dest_top, "this is read only")?;
+    make_read_only(&dest_top)?;
 
-    let mut perms = fs::metadata(&dest_top)?.permissions();
-    perms.set_readonly(true);
-    fs::set_permissions(&dest_top, perms)?;
+    let src_top = src_path.join("top.txt");
+    make_recent(&src_top)?;
+
+    let options = rusync::SyncOptions {
+        inplace: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let result = syncer.sync().unwrap();
+    assert_eq!(result.errors, 1);
+    Ok(())
+}
+
+#[test]
+fn replace_read_only_dest() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::create_dir_all(&dest_path)?;
+
+    let dest_top = dest_path.join("top.txt");
+    fs::write(&dest_top, "this is read only")?;
+    make_read_only(&dest_top)?;
 
     let src_top = src_path.join("top.txt");
     make_recent(&src_top)?;
 
+    // Temporary files are renamed over the destination, which only
+    // requires write access to the parent directory
     let syncer = new_test_syncer(&src_path, &dest_path);
     let result = syncer.sync().unwrap();
-    assert_eq!(result.errors, 1);
+    assert_eq!(result.errors, 0);
+    assert_same_contents(&src_top, &dest_top);
+    Ok(())
+}
+
+#[test]
+fn remove_leftover_temporary_files() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    // Simulate an interrupted sync
+    let leftover = dest_path.join("a_dir/.one.txt.rusync-tmp");
+    fs::write(&leftover, "partial")?;
+
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.errors, 0);
+    assert!(!leftover.exists());
     Ok(())
 }
 
@@ -205,3 +266,1454 @@ fn broken_link_in_src() -> Result<(), std::io::Error> {
     assert!(result.is_ok());
     Ok(())
 }
+
+fn new_delete_syncer(src: &Path, dest: &Path) -> rusync::Syncer {
+    let options = rusync::SyncOptions {
+        delete: true,
+        ..Default::default()
+    };
+    rusync::Syncer::new(src, dest, options, Box::new(DummyProgressInfo {}))
+}
+
+#[test]
+fn keep_extraneous_files_by_default() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    let extra = dest_path.join("extra.txt");
+    fs::write(&extra, "extra")?;
+
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    let stats = syncer.sync().unwrap();
+    assert!(extra.exists());
+    assert_eq!(stats.deleted, 0);
+    Ok(())
+}
+
+#[test]
+fn delete_extraneous_files() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    let extra = dest_path.join("a_dir/extra.txt");
+    fs::write(&extra, "extra")?;
+    let extra_dir = dest_path.join("extra_dir");
+    fs::create_dir_all(extra_dir.join("sub"))?;
+    fs::write(extra_dir.join("sub/nested.txt"), "nested")?;
+    fs::remove_file(src_path.join("top.txt"))?;
+
+    let syncer = new_delete_syncer(&src_path, &dest_path);
+    let stats = syncer.sync().unwrap();
+
+    assert!(!extra.exists());
+    assert!(!extra_dir.exists());
+    assert!(!dest_path.join("top.txt").exists());
+    assert!(dest_path.join("a_dir/one.txt").exists());
+    // extra.txt, top.txt, extra_dir, extra_dir/sub and extra_dir/sub/nested.txt
+    assert_eq!(stats.deleted, 5);
+    assert_eq!(stats.errors, 0);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn delete_symlink_to_directory_without_following_it() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    let dest_link = dest_path.join("link_to_a_dir");
+    unix::fs::symlink("a_dir", &dest_link)?;
+
+    let syncer = new_delete_syncer(&src_path, &dest_path);
+    let stats = syncer.sync().unwrap();
+
+    assert!(dest_link.symlink_metadata().is_err());
+    assert!(dest_path.join("a_dir/one.txt").exists());
+    assert_eq!(stats.deleted, 1);
+    Ok(())
+}
+
+#[test]
+fn dry_run_leaves_destination_untouched() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    let src_top = src_path.join("top.txt");
+    make_recent(&src_top)?;
+    fs::write(&src_top, "changed")?;
+    fs::write(src_path.join("new.txt"), "new")?;
+    let extra = dest_path.join("extra.txt");
+    fs::write(&extra, "extra")?;
+
+    let options = rusync::SyncOptions {
+        dry_run: true,
+        delete: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.copied, 2);
+    assert_eq!(stats.deleted, 1);
+    assert!(extra.exists());
+    assert!(!dest_path.join("new.txt").exists());
+    assert_ne!(fs::read_to_string(dest_path.join("top.txt"))?, "changed");
+    Ok(())
+}
+
+#[test]
+fn dry_run_on_missing_destination() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let options = rusync::SyncOptions {
+        dry_run: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.copied + stats.dirs_created, stats.num_files);
+    assert!(!dest_path.exists());
+    Ok(())
+}
+
+#[test]
+fn checksum_skips_files_with_fresh_mtimes() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    // Simulate a restore from backup: same contents, but more recent
+    make_recent(&src_path.join("top.txt"))?;
+    make_recent(&src_path.join("a_dir/one.txt"))?;
+
+    let options = rusync::SyncOptions {
+        checksum: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.copied, 0);
+    assert_eq!(stats.up_to_date, stats.num_files);
+    Ok(())
+}
+
+#[test]
+fn delta_transfer() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let big_src = src_path.join("big.bin");
+    let contents: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&big_src, &contents)?;
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    let mut changed = contents.clone();
+    changed[500_000] ^= 0xff;
+    std::fs::write(&big_src, &changed)?;
+    make_recent(&big_src)?;
+
+    let options = rusync::SyncOptions {
+        delta: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.copied, 1);
+    assert_same_contents(&big_src, &dest_path.join("big.bin"));
+    assert_eq!(stats.literal_bytes + stats.matched_bytes, 1_000_000);
+    assert!(stats.literal_bytes < 10_000);
+    Ok(())
+}
+
+#[test]
+fn preserve_times() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let old = FileTime::from_unix_time(1_000_000_000, 0);
+    filetime::set_file_times(src_path.join("top.txt"), old, old)?;
+    filetime::set_file_times(src_path.join("b_dir/c_dir"), old, old)?;
+
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    assert_eq!(mtime(&dest_path.join("top.txt")), old);
+    assert_eq!(mtime(&dest_path.join("b_dir/c_dir")), old);
+    assert_eq!(
+        mtime(&dest_path.join("a_dir")),
+        mtime(&src_path.join("a_dir"))
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn preserve_symlink_times_without_following_them() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let src_link = src_path.join("link_to_top");
+    unix::fs::symlink("top.txt", &src_link)?;
+    let old = FileTime::from_unix_time(1_000_000_000, 0);
+    filetime::set_symlink_file_times(&src_link, old, old)?;
+
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    assert_eq!(mtime(&dest_path.join("link_to_top")), old);
+    assert_eq!(
+        mtime(&dest_path.join("top.txt")),
+        mtime(&src_path.join("top.txt"))
+    );
+    Ok(())
+}
+
+#[test]
+fn do_not_preserve_times() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let old = FileTime::from_unix_time(1_000_000_000, 0);
+    filetime::set_file_times(src_path.join("top.txt"), old, old)?;
+
+    let options = rusync::SyncOptions {
+        preserve_times: false,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.sync().unwrap();
+
+    assert_ne!(mtime(&dest_path.join("top.txt")), old);
+    Ok(())
+}
+
+#[test]
+fn exclude_files_and_directories() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let options = rusync::SyncOptions {
+        filters: vec![
+            rusync::FilterRule::Include("one.txt".to_string()),
+            rusync::FilterRule::Exclude("*.txt".to_string()),
+            rusync::FilterRule::Exclude("/b_dir/".to_string()),
+        ],
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert!(dest_path.join("a_dir/one.txt").exists());
+    assert!(dest_path.join("a_dir/foo.exe").exists());
+    assert!(!dest_path.join("a_dir/two.txt").exists());
+    assert!(!dest_path.join("top.txt").exists());
+    assert!(!dest_path.join("b_dir").exists());
+    // a_dir, a_dir/one.txt and a_dir/foo.exe
+    assert_eq!(stats.num_files, 3);
+    Ok(())
+}
+
+#[test]
+fn invalid_filter() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let options = rusync::SyncOptions {
+        filters: vec![rusync::FilterRule::Exclude("foo[".to_string())],
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let result = syncer.sync();
+
+    assert!(result.is_err());
+    assert!(!dest_path.exists());
+    Ok(())
+}
+
+#[test]
+fn do_not_delete_excluded_files() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    let excluded = dest_path.join("a_dir/cache.tmp");
+    fs::write(&excluded, "cache")?;
+    let extra = dest_path.join("a_dir/extra.txt");
+    fs::write(&extra, "extra")?;
+
+    let options = rusync::SyncOptions {
+        delete: true,
+        filters: vec![rusync::FilterRule::Exclude("*.tmp".to_string())],
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert!(excluded.exists());
+    assert!(!extra.exists());
+    assert_eq!(stats.deleted, 1);
+    Ok(())
+}
+
+#[test]
+fn do_not_delete_excluded_files_in_deleted_directories() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    let old_dir = dest_path.join("old_dir");
+    fs::create_dir_all(old_dir.join("nested"))?;
+    fs::write(old_dir.join("nested/important.keep"), "keep")?;
+    fs::write(old_dir.join("extra.txt"), "extra")?;
+    let empty_dir = dest_path.join("empty_dir");
+    fs::create_dir_all(&empty_dir)?;
+
+    let options = rusync::SyncOptions {
+        delete: true,
+        filters: vec![rusync::FilterRule::Exclude("*.keep".to_string())],
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert!(old_dir.join("nested/important.keep").exists());
+    assert!(!old_dir.join("extra.txt").exists());
+    assert!(!empty_dir.exists());
+    assert_eq!(stats.deleted, 2);
+    assert_eq!(stats.errors, 0);
+    Ok(())
+}
+
+#[test]
+fn honor_ignore_files() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::write(src_path.join(".gitignore"), "/b_dir/\n*.exe\n")?;
+    fs::write(src_path.join("a_dir/.rusyncignore"), "two.txt\n!foo.exe\n")?;
+
+    let options = rusync::SyncOptions {
+        ignore_files: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.sync().unwrap();
+
+    assert!(dest_path.join(".gitignore").exists());
+    assert!(dest_path.join("top.txt").exists());
+    assert!(dest_path.join("a_dir/one.txt").exists());
+    assert!(dest_path.join("a_dir/foo.exe").exists());
+    assert!(!dest_path.join("a_dir/two.txt").exists());
+    assert!(!dest_path.join("b_dir").exists());
+
+    // Ignored entries are left alone when deleting
+    fs::create_dir_all(dest_path.join("b_dir"))?;
+    fs::write(dest_path.join("a_dir/two.txt"), "two")?;
+    let options = rusync::SyncOptions {
+        ignore_files: true,
+        delete: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.deleted, 0);
+    assert!(dest_path.join("b_dir").exists());
+    assert!(dest_path.join("a_dir/two.txt").exists());
+    Ok(())
+}
+
+#[test]
+fn honor_ignore_files_in_deleted_directories() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let old_dir = dest_path.join("old_dir");
+    fs::create_dir_all(old_dir.join("nested"))?;
+    fs::write(old_dir.join(".rusyncignore"), "*.keep\n")?;
+    fs::write(old_dir.join("nested/important.keep"), "keep")?;
+    fs::write(old_dir.join("extra.txt"), "extra")?;
+
+    let options = rusync::SyncOptions {
+        ignore_files: true,
+        delete: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.errors, 0);
+    assert!(old_dir.join("nested/important.keep").exists());
+    assert!(!old_dir.join("extra.txt").exists());
+    Ok(())
+}
+
+#[test]
+fn walk_continues_after_errors() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    // Ignore files must be valid UTF-8
+    fs::write(src_path.join("a_dir/.gitignore"), b"\xff\xfe\n")?;
+
+    let options = rusync::SyncOptions {
+        ignore_files: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.errors, 1);
+    assert!(!dest_path.join("a_dir/one.txt").exists());
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+    assert_same_contents(
+        &src_path.join("b_dir/c_dir/three.txt"),
+        &dest_path.join("b_dir/c_dir/three.txt"),
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn unreadable_source_directory() -> Result<(), std::io::Error> {
+    if unsafe { libc::geteuid() } == 0 {
+        // root can read any directory
+        return Ok(());
+    }
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let a_dir = src_path.join("a_dir");
+    fs::set_permissions(&a_dir, fs::Permissions::from_mode(0o000))?;
+
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    let stats = syncer.sync();
+    fs::set_permissions(&a_dir, fs::Permissions::from_mode(0o755))?;
+    let stats = stats.unwrap();
+
+    assert_eq!(stats.errors, 1);
+    assert!(!dest_path.join("a_dir/one.txt").exists());
+    assert!(dest_path.join("b_dir/c_dir/three.txt").exists());
+    Ok(())
+}
+
+/// Keeps the errors reported during the sync
+struct ErrorCollector {
+    errors: Arc<Mutex<Vec<rusync::SyncError>>>,
+}
+
+impl ProgressInfo for ErrorCollector {
+    fn error(&mut self, error: &rusync::SyncError) {
+        self.errors.lock().unwrap().push(error.clone());
+    }
+}
+
+#[test]
+fn structured_errors() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    // A file is in the way of the a_dir directory and its contents
+    fs::create_dir_all(&dest_path)?;
+    fs::write(dest_path.join("a_dir"), "not a directory")?;
+
+    let errors = Arc::new(Mutex::new(vec![]));
+    let collector = ErrorCollector {
+        errors: errors.clone(),
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        rusync::SyncOptions::default(),
+        Box::new(collector),
+    );
+    let stats = syncer.sync().unwrap();
+
+    let errors = errors.lock().unwrap();
+    assert_eq!(stats.errors as usize, errors.len());
+    let dir_error = errors.iter().find(|e| e.entry == "a_dir").unwrap();
+    assert_eq!(dir_error.phase, rusync::ErrorPhase::Write);
+    assert_eq!(dir_error.errno, None);
+    let file_error = errors.iter().find(|e| e.entry == "a_dir/one.txt").unwrap();
+    assert_eq!(file_error.phase, rusync::ErrorPhase::Write);
+    assert!(file_error.errno.is_some());
+    assert!(!file_error.is_transient());
+    Ok(())
+}
+
+/// A writer whose contents can be read once the sync is done
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn json_progress() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+
+    let buffer = SharedBuffer::default();
+    let json_info = rusync::JsonProgressInfo::with_writer(Box::new(buffer.clone()));
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        rusync::SyncOptions::default(),
+        Box::new(json_info),
+    );
+    let stats = syncer.sync().unwrap();
+
+    let contents = buffer.0.lock().unwrap();
+    let events: Vec<serde_json::Value> = String::from_utf8_lossy(&contents)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(events.first().unwrap()["event"], "start");
+    let end = events.last().unwrap();
+    assert_eq!(end["event"], "end");
+    assert_eq!(end["copied"], stats.copied);
+    let synced: Vec<_> = events.iter().filter(|e| e["event"] == "synced").collect();
+    assert_eq!(synced.len() as u64, stats.num_synced);
+    assert!(synced
+        .iter()
+        .any(|e| e["entry"] == "top.txt" && e["outcome"] == "copied"));
+    Ok(())
+}
+
+#[test]
+fn log_file() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let log_path = tmp_dir.path().join("rusync.log");
+
+    let new_log_syncer = || {
+        let format = rusync::OutFormat::parse("%o %n %l").unwrap();
+        let log_info = rusync::LogProgressInfo::new(&log_path, format).unwrap();
+        rusync::Syncer::new(
+            &src_path,
+            &dest_path,
+            rusync::SyncOptions::default(),
+            Box::new(log_info),
+        )
+    };
+    new_log_syncer().sync().unwrap();
+    // Up to date entries are not logged, and the log file is appended to
+    new_log_syncer().sync().unwrap();
+
+    let log = fs::read_to_string(&log_path)?;
+    let lines: Vec<_> = log.lines().collect();
+    let top_size = fs::metadata(src_path.join("top.txt"))?.len();
+    let expected = format!("copied top.txt {}", top_size);
+    assert_eq!(lines.iter().filter(|l| **l == expected).count(), 1);
+    assert_eq!(
+        lines.iter().filter(|l| l.contains("Syncing from")).count(),
+        2
+    );
+    assert_eq!(lines.iter().filter(|l| l.contains("Synced")).count(), 2);
+    Ok(())
+}
+
+#[test]
+fn combine_progress_infos() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::create_dir_all(&dest_path)?;
+    fs::write(dest_path.join("a_dir"), "not a directory")?;
+
+    let json_buffer = SharedBuffer::default();
+    let json_info = rusync::JsonProgressInfo::with_writer(Box::new(json_buffer.clone()));
+    let errors_buffer = SharedBuffer::default();
+    let error_list = rusync::ErrorListProgressInfo::with_writer(
+        Box::new(errors_buffer.clone()),
+        rusync::ErrorListFormat::Names,
+    );
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        rusync::SyncOptions::default(),
+        Box::new(json_info),
+    )
+    .add_progress_info(Box::new(error_list));
+    let stats = syncer.sync().unwrap();
+
+    let json = json_buffer.0.lock().unwrap();
+    let json = String::from_utf8_lossy(&json);
+    let json_errors = json
+        .lines()
+        .filter(|line| line.contains(r#""event":"error""#))
+        .count();
+    let error_list = errors_buffer.0.lock().unwrap();
+    let error_list = String::from_utf8_lossy(&error_list);
+    let error_names: Vec<_> = error_list.lines().collect();
+    assert_eq!(json_errors as u64, stats.errors);
+    assert_eq!(error_names.len() as u64, stats.errors);
+    assert!(error_names.contains(&"a_dir"));
+    Ok(())
+}
+
+/// Keeps the outcome of each synced entry
+struct OutcomeCollector {
+    outcomes: Arc<Mutex<Vec<(String, rusync::SyncOutcome)>>>,
+}
+
+impl ProgressInfo for OutcomeCollector {
+    fn synced(&mut self, name: &str, outcome: &rusync::SyncOutcome) {
+        let outcome = (name.to_string(), outcome.clone());
+        self.outcomes.lock().unwrap().push(outcome);
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn report_changed_attributes() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    fs::set_permissions(src_path.join("top.txt"), fs::Permissions::from_mode(0o600))?;
+    fs::write(src_path.join("a_dir/one.txt"), "one, but longer")?;
+    let outcomes = Arc::new(Mutex::new(vec![]));
+    let collector = OutcomeCollector {
+        outcomes: outcomes.clone(),
+    };
+    let options = rusync::SyncOptions {
+        preserve_permissions: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(&src_path, &dest_path, options, Box::new(collector));
+    syncer.sync().unwrap();
+
+    let outcomes = outcomes.lock().unwrap();
+    let outcome = |name: &str| {
+        outcomes
+            .iter()
+            .find(|(entry, _)| entry == name)
+            .map(|(_, outcome)| outcome.clone())
+            .unwrap()
+    };
+    assert_eq!(outcome("top.txt").itemize().unwrap(), ".f...p");
+    assert_eq!(outcome("a_dir/one.txt").itemize().unwrap(), ">f.st.");
+    assert_eq!(
+        outcome("b_dir/c_dir/three.txt"),
+        rusync::SyncOutcome::UpToDate
+    );
+    Ok(())
+}
+
+#[test]
+fn plan_then_execute() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    let plan = syncer.plan().unwrap();
+    assert!(!dest_path.exists());
+    assert!(plan.errors.is_empty());
+    let top = plan.actions.iter().find(|a| a.entry == "top.txt").unwrap();
+    let top_size = fs::metadata(src_path.join("top.txt"))?.len();
+    assert_eq!(top.action, rusync::Action::Copy { size: top_size });
+    assert!(top.reasons.created);
+    let c_dir = plan
+        .actions
+        .iter()
+        .find(|a| a.entry == "b_dir/c_dir")
+        .unwrap();
+    assert_eq!(c_dir.action, rusync::Action::CreateDir);
+
+    let stats = syncer.execute(&plan).unwrap();
+    assert_eq!(stats.num_synced as usize, plan.actions.len());
+    assert_same_contents(&src_path.join("top.txt"), &dest_path.join("top.txt"));
+
+    // Directory times were restored, so there is nothing left to do
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    assert!(syncer.plan().unwrap().is_empty());
+    Ok(())
+}
+
+#[test]
+fn execute_filtered_plan() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::create_dir_all(dest_path.join("a_dir"))?;
+    fs::write(dest_path.join("a_dir/old.txt"), "old")?;
+    fs::write(dest_path.join("stale.txt"), "stale")?;
+
+    let syncer = new_delete_syncer(&src_path, &dest_path);
+    let mut plan = syncer.plan().unwrap();
+    let deleted: Vec<_> = plan
+        .actions
+        .iter()
+        .filter(|a| a.action == rusync::Action::Delete)
+        .map(|a| a.entry.as_str())
+        .collect();
+    assert_eq!(deleted, ["a_dir/old.txt", "stale.txt"]);
+
+    // Approve the changes to a_dir only
+    plan.actions.retain(|a| a.entry.starts_with("a_dir/"));
+    let stats = syncer.execute(&plan).unwrap();
+
+    assert!(dest_path.join("a_dir/one.txt").exists());
+    assert!(!dest_path.join("a_dir/old.txt").exists());
+    assert!(dest_path.join("stale.txt").exists());
+    assert!(!dest_path.join("top.txt").exists());
+    assert!(!dest_path.join("b_dir").exists());
+    assert_eq!(stats.deleted, 1);
+    Ok(())
+}
+
+fn setup_many_files(tmp_path: &Path, count: usize) -> io::Result<(PathBuf, PathBuf)> {
+    let src_path = tmp_path.join("src");
+    fs::create_dir_all(&src_path)?;
+    for i in 0..count {
+        fs::write(src_path.join(format!("{:03}.txt", i)), "some contents")?;
+    }
+    Ok((src_path, tmp_path.join("dest")))
+}
+
+#[test]
+fn pause_and_resume() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_many_files(tmp_dir.path(), 100)?;
+
+    let handle = new_test_syncer(&src_path, &dest_path).spawn();
+    handle.pause();
+    std::thread::sleep(std::time::Duration::from_millis(100));
+    assert!(!handle.is_finished());
+    handle.resume();
+    let stats = handle.join().unwrap();
+
+    assert!(!stats.cancelled);
+    assert_eq!(stats.copied, 100);
+    Ok(())
+}
+
+#[test]
+fn cancel_sync() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_many_files(tmp_dir.path(), 100)?;
+
+    let handle = new_test_syncer(&src_path, &dest_path).spawn();
+    handle.cancel();
+    let stats = handle.join().unwrap();
+
+    assert!(stats.cancelled);
+    assert_eq!(stats.errors, 0);
+    assert!(stats.copied < 100);
+    // Entries are either fully synced or left alone
+    let mut num_copied = 0;
+    for entry in fs::read_dir(&dest_path)? {
+        let entry = entry?;
+        assert_eq!(fs::read_to_string(entry.path())?, "some contents");
+        num_copied += 1;
+    }
+    assert_eq!(num_copied, stats.copied);
+    Ok(())
+}
+
+#[test]
+fn replicate_empty_directories() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::create_dir_all(src_path.join("empty/nested"))?;
+
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    let stats = syncer.sync().unwrap();
+
+    assert!(dest_path.join("empty/nested").is_dir());
+    // a_dir, b_dir, b_dir/c_dir, empty and empty/nested
+    assert_eq!(stats.dirs_created, 5);
+
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.dirs_created, 0);
+    Ok(())
+}
+
+#[test]
+fn create_destination_for_empty_source() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let src_path = tmp_dir.path().join("src");
+    let dest_path = tmp_dir.path().join("dest");
+    fs::create_dir(&src_path)?;
+
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    syncer.sync().unwrap();
+
+    assert!(dest_path.is_dir());
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn preserve_directory_metadata() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let src_dir = src_path.join("a_dir");
+    let old = FileTime::from_unix_time(1_000_000_000, 0);
+    filetime::set_file_times(&src_dir, old, old)?;
+    // Contents must still be written even if the directory is read-only
+    fs::set_permissions(&src_dir, fs::Permissions::from_mode(0o555))?;
+
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    let stats = syncer.sync().unwrap();
+
+    let dest_dir = dest_path.join("a_dir");
+    let mode = fs::metadata(&dest_dir)?.permissions().mode();
+    fs::set_permissions(&src_dir, fs::Permissions::from_mode(0o755))?;
+    fs::set_permissions(&dest_dir, fs::Permissions::from_mode(0o755))?;
+
+    assert_eq!(stats.errors, 0);
+    assert_eq!(mode & 0o777, 0o555);
+    assert_eq!(mtime(&dest_dir), old);
+    assert!(dest_dir.join("one.txt").exists());
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn leave_entries_in_the_way_of_directories_alone() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let src_path = tmp_dir.path().join("src");
+    let dest_path = tmp_dir.path().join("dest");
+    let outside = tmp_dir.path().join("outside");
+    for name in ["file", "link"] {
+        fs::create_dir_all(src_path.join(name))?;
+        fs::set_permissions(src_path.join(name), fs::Permissions::from_mode(0o700))?;
+    }
+    fs::create_dir_all(&dest_path)?;
+    fs::create_dir_all(&outside)?;
+    fs::set_permissions(&outside, fs::Permissions::from_mode(0o755))?;
+    fs::write(dest_path.join("file"), "in the way")?;
+    fs::set_permissions(dest_path.join("file"), fs::Permissions::from_mode(0o644))?;
+    unix::fs::symlink("../outside", dest_path.join("link"))?;
+
+    let stats = new_test_syncer(&src_path, &dest_path).sync().unwrap();
+
+    assert_eq!(stats.errors, 2);
+    let file_mode = fs::symlink_metadata(dest_path.join("file"))?
+        .permissions()
+        .mode();
+    assert_eq!(file_mode & 0o777, 0o644);
+    let outside_mode = fs::metadata(&outside)?.permissions().mode();
+    assert_eq!(outside_mode & 0o777, 0o755);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn sync_links_to_directories_as_links() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    unix::fs::symlink("a_dir", src_path.join("link_to_a_dir"))?;
+
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    let stats = syncer.sync().unwrap();
+
+    let dest_link = dest_path.join("link_to_a_dir");
+    assert_eq!(dest_link.read_link()?.to_string_lossy(), "a_dir");
+    assert_eq!(stats.symlink_created, 1);
+    Ok(())
+}
+
+#[test]
+fn parallel_sync() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    for i in 0..50 {
+        let dir = src_path.join(format!("many/{}", i % 5));
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join(format!("{}.txt", i)), "x".repeat(i * 1000))?;
+    }
+    let options = rusync::SyncOptions {
+        jobs: 4,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.num_synced, stats.num_files);
+    // 50 new files, and the 5 files of the test data
+    assert_eq!(stats.copied, 55);
+    for i in 0..50 {
+        let rel_path = format!("many/{}/{}.txt", i % 5, i);
+        assert_same_contents(&src_path.join(&rel_path), &dest_path.join(&rel_path));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn inode(path: &Path) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).unwrap().ino()
+}
+
+#[cfg(unix)]
+fn new_hard_links_syncer(src: &Path, dest: &Path) -> rusync::Syncer {
+    let options = rusync::SyncOptions {
+        hard_links: true,
+        ..Default::default()
+    };
+    rusync::Syncer::new(src, dest, options, Box::new(DummyProgressInfo {}))
+}
+
+#[test]
+#[cfg(unix)]
+fn preserve_hard_links() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::hard_link(
+        src_path.join("top.txt"),
+        src_path.join("b_dir/top_link.txt"),
+    )?;
+
+    let syncer = new_hard_links_syncer(&src_path, &dest_path);
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.copied, 5);
+    assert_eq!(stats.hard_linked, 1);
+    assert_eq!(stats.num_synced, stats.num_files);
+    let dest_link = dest_path.join("b_dir/top_link.txt");
+    assert_same_contents(&src_path.join("top.txt"), &dest_link);
+    assert_eq!(inode(&dest_path.join("top.txt")), inode(&dest_link));
+
+    let syncer = new_hard_links_syncer(&src_path, &dest_path);
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.hard_linked, 0);
+    assert_eq!(stats.up_to_date, stats.num_files);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn execute_hard_links_without_their_targets() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::hard_link(
+        src_path.join("top.txt"),
+        src_path.join("b_dir/top_link.txt"),
+    )?;
+
+    let syncer = new_hard_links_syncer(&src_path, &dest_path);
+    let mut plan = syncer.plan().unwrap();
+    plan.actions
+        .retain(|a| a.action == rusync::Action::HardLink);
+    assert_eq!(plan.actions.len(), 1);
+    let linked = dest_path.join(&plan.actions[0].entry);
+    let stats = syncer.execute(&plan).unwrap();
+
+    // The file it was linked to is not synced, so it is copied instead
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.copied, 1);
+    assert_same_contents(&src_path.join("top.txt"), &linked);
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn hard_links_replace_existing_copies() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    fs::hard_link(src_path.join("top.txt"), src_path.join("top_link.txt"))?;
+
+    // Without the option, each link is copied
+    let syncer = new_test_syncer(&src_path, &dest_path);
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.copied, 6);
+    let dest_link = dest_path.join("top_link.txt");
+    assert_ne!(inode(&dest_path.join("top.txt")), inode(&dest_link));
+
+    let syncer = new_hard_links_syncer(&src_path, &dest_path);
+    let stats = syncer.sync().unwrap();
+    assert_eq!(stats.hard_linked, 1);
+    assert_eq!(inode(&dest_path.join("top.txt")), inode(&dest_link));
+    Ok(())
+}
+
+#[cfg(unix)]
+fn allocated_size(path: &Path) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    std::fs::metadata(path).unwrap().blocks() * 512
+}
+
+#[test]
+#[cfg(unix)]
+fn preserve_sparse_files() -> Result<(), std::io::Error> {
+    use std::io::{Seek, SeekFrom, Write};
+
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let image = src_path.join("disk.img");
+    let mut image_file = fs::File::create(&image)?;
+    image_file.set_len(20 * 1024 * 1024)?;
+    image_file.seek(SeekFrom::Start(10 * 1024 * 1024))?;
+    image_file.write_all(b"some data")?;
+    drop(image_file);
+
+    let options = rusync::SyncOptions {
+        reflink: rusync::ReflinkMode::Never,
+        ..Default::default()
+    };
+    let dense_dest = tmp_dir.path().join("dense");
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dense_dest,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.sync().unwrap();
+
+    let options = rusync::SyncOptions {
+        sparse: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    syncer.sync().unwrap();
+
+    let dest_image = dest_path.join("disk.img");
+    assert_same_contents(&image, &dest_image);
+    // How much a dense copy takes depends on the filesystem, which may
+    // compress or deduplicate blocks of zeros
+    let sparse_size = allocated_size(&dest_image);
+    assert!(sparse_size <= allocated_size(&image));
+    assert!(sparse_size <= allocated_size(&dense_dest.join("disk.img")));
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn preserve_xattrs() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    xattr::set(src_path.join("top.txt"), "user.color", b"blue")?;
+    xattr::set(src_path.join("a_dir"), "user.color", b"red")?;
+
+    let options = rusync::SyncOptions {
+        xattrs: true,
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    assert_eq!(stats.errors, 0);
+    assert_eq!(
+        xattr::get(dest_path.join("top.txt"), "user.color")?,
+        Some(b"blue".to_vec())
+    );
+    assert_eq!(
+        xattr::get(dest_path.join("a_dir"), "user.color")?,
+        Some(b"red".to_vec())
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn preserve_mapped_ownership() -> Result<(), std::io::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let options = rusync::SyncOptions {
+        owner: true,
+        group: true,
+        numeric_ids: true,
+        usermap: vec!["*:12345".parse().unwrap()],
+        groupmap: vec!["*:23456".parse().unwrap()],
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    let stats = syncer.sync().unwrap();
+
+    let dest_top = dest_path.join("top.txt");
+    assert_same_contents(&src_path.join("top.txt"), &dest_top);
+    assert_eq!(stats.num_synced, stats.num_files);
+    let is_root = unsafe { libc::geteuid() } == 0;
+    if is_root {
+        assert_eq!(stats.errors, 0);
+        let metadata = fs::metadata(&dest_top)?;
+        assert_eq!((metadata.uid(), metadata.gid()), (12345, 23456));
+        let metadata = fs::metadata(dest_path.join("a_dir"))?;
+        assert_eq!((metadata.uid(), metadata.gid()), (12345, 23456));
+    } else {
+        // Not permitted: errors are reported, but files are still synced
+        assert!(stats.errors > 0);
+    }
+    Ok(())
+}
+
+#[test]
+fn invalid_id_mapping() {
+    let tmp_dir = TempDir::new().unwrap();
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let options = rusync::SyncOptions {
+        owner: true,
+        numeric_ids: true,
+        usermap: vec!["*:root".parse().unwrap()],
+        ..Default::default()
+    };
+    let syncer = rusync::Syncer::new(
+        &src_path,
+        &dest_path,
+        options,
+        Box::new(DummyProgressInfo {}),
+    );
+    assert!(syncer.sync().is_err());
+}
+
+fn setup_sources(tmp_path: &Path) -> Result<PathBuf, std::io::Error> {
+    let src_path = tmp_path.join("src");
+    fs::create_dir_all(src_path.join("dir1/sub"))?;
+    fs::create_dir_all(src_path.join("dir2"))?;
+    fs::write(src_path.join("a.txt"), "a")?;
+    fs::write(src_path.join("dir1/sub/one.txt"), "one")?;
+    fs::write(src_path.join("dir2/two.txt"), "two")?;
+    Ok(src_path)
+}
+
+fn new_sources_syncer(sources: &[PathBuf], dest: &Path, delete: bool) -> rusync::Syncer {
+    let options = rusync::SyncOptions {
+        delete,
+        ..Default::default()
+    };
+    rusync::Syncer::with_sources(sources, dest, options, Box::new(DummyProgressInfo {}))
+}
+
+fn with_trailing_slash(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push("/");
+    PathBuf::from(path)
+}
+
+#[test]
+fn multiple_sources_with_trailing_slash() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let src_path = setup_sources(tmp_dir.path())?;
+    let dest_path = tmp_dir.path().join("dest");
+    let sources = [
+        src_path.join("a.txt"),
+        src_path.join("dir1"),
+        with_trailing_slash(&src_path.join("dir2")),
+    ];
+
+    let stats = new_sources_syncer(&sources, &dest_path, false)
+        .sync()
+        .unwrap();
+
+    assert_same_contents(&src_path.join("a.txt"), &dest_path.join("a.txt"));
+    assert_same_contents(
+        &src_path.join("dir1/sub/one.txt"),
+        &dest_path.join("dir1/sub/one.txt"),
+    );
+    assert_same_contents(&src_path.join("dir2/two.txt"), &dest_path.join("two.txt"));
+    assert!(!dest_path.join("dir2").exists());
+    // a.txt, dir1, dir1/sub, dir1/sub/one.txt and two.txt
+    assert_eq!(stats.num_files, 5);
+    assert_eq!(stats.copied, 3);
+    assert_eq!(stats.dirs_created, 2);
+    Ok(())
+}
+
+#[test]
+fn single_file_to_new_name() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let src_path = setup_sources(tmp_dir.path())?;
+    let dest_path = tmp_dir.path().join("renamed.txt");
+
+    let stats = new_sources_syncer(&[src_path.join("a.txt")], &dest_path, false)
+        .sync()
+        .unwrap();
+
+    assert_same_contents(&src_path.join("a.txt"), &dest_path);
+    assert_eq!(stats.copied, 1);
+    Ok(())
+}
+
+#[test]
+fn single_file_in_directory() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let src_path = setup_sources(tmp_dir.path())?;
+    let existing_dest = tmp_dir.path().join("existing");
+    fs::create_dir(&existing_dest)?;
+    let new_dest = tmp_dir.path().join("new");
+
+    new_sources_syncer(&[src_path.join("a.txt")], &existing_dest, false)
+        .sync()
+        .unwrap();
+    new_sources_syncer(
+        &[src_path.join("a.txt")],
+        &with_trailing_slash(&new_dest),
+        false,
+    )
+    .sync()
+    .unwrap();
+
+    assert_same_contents(&src_path.join("a.txt"), &existing_dest.join("a.txt"));
+    assert_same_contents(&src_path.join("a.txt"), &new_dest.join("a.txt"));
+    Ok(())
+}
+
+#[test]
+fn delete_with_multiple_sources() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let src_path = setup_sources(tmp_dir.path())?;
+    let dest_path = tmp_dir.path().join("dest");
+    let sources = [
+        src_path.join("a.txt"),
+        src_path.join("dir1"),
+        with_trailing_slash(&src_path.join("dir2")),
+    ];
+    new_sources_syncer(&sources, &dest_path, false)
+        .sync()
+        .unwrap();
+    fs::write(dest_path.join("extra.txt"), "extra")?;
+    fs::write(dest_path.join("dir1/sub/extra.txt"), "extra")?;
+
+    let stats = new_sources_syncer(&sources, &dest_path, true)
+        .sync()
+        .unwrap();
+
+    assert_eq!(stats.deleted, 2);
+    assert!(!dest_path.join("extra.txt").exists());
+    assert!(!dest_path.join("dir1/sub/extra.txt").exists());
+    assert!(dest_path.join("a.txt").exists());
+    assert!(dest_path.join("dir1/sub/one.txt").exists());
+    assert!(dest_path.join("two.txt").exists());
+    Ok(())
+}
+
+#[test]
+fn missing_source() {
+    let tmp_dir = TempDir::new().unwrap();
+    let src_path = tmp_dir.path().join("nosuch");
+    let dest_path = tmp_dir.path().join("dest");
+
+    let result = new_sources_syncer(&[src_path], &dest_path, false).sync();
+
+    assert!(result.is_err());
+}
+
+#[test]
+fn report_missing_source_and_sync_the_others() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let src_path = setup_sources(tmp_dir.path())?;
+    let dest_path = tmp_dir.path().join("dest");
+    fs::create_dir_all(dest_path.join("nosuch"))?;
+    // The whole destination is walked when deleting
+    let sources = [
+        with_trailing_slash(&src_path.join("dir2")),
+        src_path.join("nosuch"),
+    ];
+
+    let stats = new_sources_syncer(&sources, &dest_path, true)
+        .sync()
+        .unwrap();
+
+    assert_eq!(stats.errors, 1);
+    assert_eq!(stats.deleted, 0);
+    assert!(dest_path.join("two.txt").exists());
+    assert!(dest_path.join("nosuch").exists());
+    Ok(())
+}
+
+fn setup_memory_test() -> io::Result<rusync::MemoryFilesystem> {
+    use rusync::Filesystem;
+
+    let filesystem = rusync::MemoryFilesystem::new();
+    filesystem.write("/src/top.txt", "top")?;
+    filesystem.write("/src/a_dir/one.txt", "one")?;
+    filesystem.write("/src/a_dir/two.txt", "two")?;
+    filesystem.symlink(Path::new("top.txt"), Path::new("/src/link"))?;
+    filesystem.set_permissions(Path::new("/src/a_dir/two.txt"), 0o755)?;
+    Ok(filesystem)
+}
+
+fn new_memory_syncer(filesystem: &rusync::MemoryFilesystem, delete: bool) -> rusync::Syncer {
+    let options = rusync::SyncOptions {
+        delete,
+        hard_links: true,
+        ..Default::default()
+    };
+    rusync::Syncer::new(
+        Path::new("/src"),
+        Path::new("/dest"),
+        options,
+        Box::new(DummyProgressInfo {}),
+    )
+    .use_filesystem(Arc::new(filesystem.clone()))
+}
+
+#[test]
+fn sync_in_memory() -> Result<(), std::io::Error> {
+    use rusync::Filesystem;
+
+    let filesystem = setup_memory_test()?;
+    filesystem.hard_link(
+        Path::new("/src/top.txt"),
+        Path::new("/src/a_dir/top_link.txt"),
+    )?;
+
+    let stats = new_memory_syncer(&filesystem, false).sync().unwrap();
+
+    assert_eq!(stats.errors, 0);
+    assert_eq!(stats.copied, 3);
+    assert_eq!(stats.hard_linked, 1);
+    assert_eq!(stats.symlink_created, 1);
+    assert_eq!(filesystem.read("/dest/a_dir/one.txt")?, b"one");
+    assert_eq!(
+        filesystem.read_link(Path::new("/dest/link"))?,
+        PathBuf::from("top.txt")
+    );
+    let dest_two = filesystem.metadata(Path::new("/dest/a_dir/two.txt"))?;
+    assert_eq!(dest_two.mode, 0o755);
+    let src_two = filesystem.metadata(Path::new("/src/a_dir/two.txt"))?;
+    assert_eq!(dest_two.modified, src_two.modified);
+    let dest_top = filesystem.metadata(Path::new("/dest/top.txt"))?;
+    let dest_link = filesystem.metadata(Path::new("/dest/a_dir/top_link.txt"))?;
+    assert_eq!(dest_top.ino, dest_link.ino);
+
+    filesystem.write("/dest/extra.txt", "extra")?;
+    let stats = new_memory_syncer(&filesystem, true).sync().unwrap();
+
+    assert_eq!(stats.up_to_date, stats.num_files);
+    assert_eq!(stats.deleted, 1);
+    assert!(filesystem.metadata(Path::new("/dest/extra.txt")).is_err());
+    Ok(())
+}
+
+#[test]
+fn report_filesystem_errors() -> Result<(), std::io::Error> {
+    let filesystem = setup_memory_test()?;
+    filesystem.inject_error("/src/a_dir/one.txt", io::ErrorKind::PermissionDenied);
+    let errors = Arc::new(Mutex::new(vec![]));
+    let collector = ErrorCollector {
+        errors: errors.clone(),
+    };
+
+    let stats = new_memory_syncer(&filesystem, false)
+        .add_progress_info(Box::new(collector))
+        .sync()
+        .unwrap();
+
+    assert_eq!(stats.errors, 1);
+    let errors = errors.lock().unwrap();
+    assert_eq!(errors[0].entry, "a_dir/one.txt");
+    assert_eq!(filesystem.read("/dest/a_dir/two.txt")?, b"two");
+    assert_eq!(filesystem.read("/dest/top.txt")?, b"top");
+    Ok(())
+}
+
+#[test]
+fn sync_local_source_to_memory_destination() -> Result<(), std::io::Error> {
+    let tmp_dir = TempDir::new()?;
+    let (src_path, dest_path) = setup_test(tmp_dir.path());
+    let filesystem = rusync::MemoryFilesystem::new();
+
+    let stats = new_test_syncer(&src_path, &dest_path)
+        .use_filesystems(
+            Arc::new(rusync::LocalFilesystem::new()),
+            Arc::new(filesystem.clone()),
+        )
+        .sync()
+        .unwrap();
+
+    assert_eq!(stats.errors, 0);
+    assert!(!dest_path.exists());
+    let contents = fs::read(src_path.join("a_dir/one.txt"))?;
+    assert_eq!(filesystem.read(dest_path.join("a_dir/one.txt"))?, contents);
+    Ok(())
+}
@@ -14,7 +14,7 @@ cleanup_dest() {
 }
 
 run_rusync() {
-  cargo run --quiet $src $dest
+  cargo run --quiet $src/ $dest
 }
 
 change_link_dest() {